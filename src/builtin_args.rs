@@ -0,0 +1,319 @@
+use crate::{diagnostics::CheckContext, lookup, utils};
+use lsp_types::{Diagnostic, DiagnosticSeverity};
+use rnix::{types::*, value::Value as ParsedValue, SyntaxNode, TextRange};
+use std::convert::TryFrom;
+
+/// Builtins that may return anything, including functions that take
+/// further arguments
+const MAY_RETURN_FUNCTIONS: &[&str] = &[
+    "addErrorContext",
+    "deepSeq",
+    "elemAt",
+    "foldl'",
+    "getAttr",
+    "head",
+    "import",
+    "scopedImport",
+    "seq",
+    "trace",
+];
+
+/// The kind of value a literal evaluates to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Bool,
+    Function,
+    List,
+    Null,
+    Number,
+    Path,
+    Set,
+    String,
+}
+
+impl Kind {
+    fn describe(self) -> &'static str {
+        match self {
+            Kind::Bool => "a boolean",
+            Kind::Function => "a function",
+            Kind::List => "a list",
+            Kind::Null => "null",
+            Kind::Number => "a number",
+            Kind::Path => "a path",
+            Kind::Set => "an attribute set",
+            Kind::String => "a string",
+        }
+    }
+}
+
+const FUNCTION: &[Kind] = &[Kind::Function, Kind::Set];
+const LIST: &[Kind] = &[Kind::List];
+const NUMBER: &[Kind] = &[Kind::Number];
+const SET: &[Kind] = &[Kind::Set];
+const STRING: &[Kind] = &[Kind::String, Kind::Path];
+const COMPARABLE: &[Kind] = &[Kind::Number, Kind::String, Kind::Path];
+
+/// What the argument at `index` of a builtin has to be. The first kind is
+/// the one to mention in errors; sets are accepted as functions since they
+/// may have a `__functor`, and paths are accepted as strings.
+fn expected_kinds(builtin: &str, index: usize) -> Option<&'static [Kind]> {
+    Some(match (builtin, index) {
+        ("all", 0)
+        | ("any", 0)
+        | ("concatMap", 0)
+        | ("filter", 0)
+        | ("foldl'", 0)
+        | ("genList", 0)
+        | ("map", 0)
+        | ("mapAttrs", 0)
+        | ("partition", 0)
+        | ("sort", 0) => FUNCTION,
+        ("concatLists", 0)
+        | ("elemAt", 0)
+        | ("head", 0)
+        | ("length", 0)
+        | ("listToAttrs", 0)
+        | ("replaceStrings", 0)
+        | ("replaceStrings", 1)
+        | ("tail", 0)
+        | ("all", 1)
+        | ("any", 1)
+        | ("catAttrs", 1)
+        | ("concatMap", 1)
+        | ("concatStringsSep", 1)
+        | ("elem", 1)
+        | ("filter", 1)
+        | ("map", 1)
+        | ("partition", 1)
+        | ("removeAttrs", 1)
+        | ("sort", 1)
+        | ("foldl'", 2) => LIST,
+        ("attrNames", 0)
+        | ("attrValues", 0)
+        | ("intersectAttrs", 0)
+        | ("removeAttrs", 0)
+        | ("getAttr", 1)
+        | ("hasAttr", 1)
+        | ("intersectAttrs", 1)
+        | ("mapAttrs", 1) => SET,
+        ("catAttrs", 0)
+        | ("concatStringsSep", 0)
+        | ("fromJSON", 0)
+        | ("getAttr", 0)
+        | ("hasAttr", 0)
+        | ("match", 0)
+        | ("split", 0)
+        | ("stringLength", 0)
+        | ("match", 1)
+        | ("split", 1)
+        | ("replaceStrings", 2)
+        | ("substring", 2) => STRING,
+        ("add", 0)
+        | ("div", 0)
+        | ("mul", 0)
+        | ("sub", 0)
+        | ("substring", 0)
+        | ("add", 1)
+        | ("div", 1)
+        | ("elemAt", 1)
+        | ("genList", 1)
+        | ("mul", 1)
+        | ("sub", 1)
+        | ("substring", 1) => NUMBER,
+        ("lessThan", 0) | ("lessThan", 1) => COMPARABLE,
+        _ => return None,
+    })
+}
+
+/// The kind of a literal, or `None` for anything that needs evaluating
+fn literal_kind(context: &CheckContext, node: &SyntaxNode) -> Option<Kind> {
+    match ParsedType::try_from(node.clone()).ok()? {
+        ParsedType::Paren(paren) => literal_kind(context, &paren.inner()?),
+        ParsedType::List(_) => Some(Kind::List),
+        ParsedType::AttrSet(_) => Some(Kind::Set),
+        ParsedType::Str(_) => Some(Kind::String),
+        ParsedType::Lambda(_) => Some(Kind::Function),
+        ParsedType::Value(value) => match value.to_value().ok()? {
+            ParsedValue::Float(_) | ParsedValue::Integer(_) => Some(Kind::Number),
+            ParsedValue::Path(..) => Some(Kind::Path),
+            ParsedValue::String(_) => Some(Kind::String),
+        },
+        ParsedType::Ident(ident) => {
+            let unshadowed = utils::scope_for(context.file, node.clone())
+                .map_or(true, |scope| !scope.contains_key(ident.as_str()));
+            match ident.as_str() {
+                "true" | "false" if unshadowed => Some(Kind::Bool),
+                "null" if unshadowed => Some(Kind::Null),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether `builtin` applied to `args` can't return a function, even though
+/// it's in `MAY_RETURN_FUNCTIONS`: picking from a literal list of values
+/// that are known not to be functions.
+fn returns_plain_value(context: &CheckContext, builtin: &str, args: &[SyntaxNode]) -> bool {
+    if builtin != "elemAt" && builtin != "head" {
+        return false;
+    }
+    let list = match args.first().map(|arg| ParsedType::try_from(arg.clone())) {
+        Some(Ok(ParsedType::List(list))) => list,
+        _ => return false,
+    };
+    list.items()
+        .all(|item| literal_kind(context, &item).map_or(false, |kind| !FUNCTION.contains(&kind)))
+}
+
+/// Splits `f a b` into `f` and `[a, b]`
+fn unapply(node: &SyntaxNode) -> Option<(SyntaxNode, Vec<SyntaxNode>)> {
+    let mut args = Vec::new();
+    let mut head = node.clone();
+    while let Some(apply) = Apply::cast(head.clone()) {
+        args.push(apply.value()?);
+        head = apply.lambda()?;
+    }
+    args.reverse();
+    Some((head, args))
+}
+
+/// Checks calls of builtins against their arity, and literal arguments
+/// against the kinds of values the builtin takes.
+pub fn builtin_arguments(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    for apply in context.ast.node().descendants().filter_map(Apply::cast) {
+        // Only look at each chain of applications once, from the outside
+        let nested = apply
+            .node()
+            .parent()
+            .and_then(Apply::cast)
+            .and_then(|parent| parent.lambda())
+            .map_or(false, |lambda| lambda == *apply.node());
+        if nested {
+            continue;
+        }
+
+        let (head, args) = match unapply(apply.node()) {
+            Some(application) => application,
+            None => continue,
+        };
+        let name = match lookup::builtin_name(context.file, &head) {
+            Some(name) => name,
+            None => continue,
+        };
+        let arity = context
+            .builtins
+            .get(&name)
+            .and_then(|details| details.args.as_ref())
+            .map(Vec::len);
+
+        for (index, arg) in args.iter().enumerate() {
+            let expected = match expected_kinds(&name, index) {
+                Some(expected) => expected,
+                None => continue,
+            };
+            match literal_kind(context, arg) {
+                Some(kind) if !expected.contains(&kind) => diagnostics.push(context.diagnostic(
+                    arg,
+                    DiagnosticSeverity::Error,
+                    format!(
+                        "`{}` expects {}, but this is {}",
+                        head.text(),
+                        expected[0].describe(),
+                        kind.describe()
+                    ),
+                )),
+                _ => (),
+            }
+        }
+
+        if let Some(arity) = arity {
+            let may_return_function = MAY_RETURN_FUNCTIONS.contains(&name.as_str())
+                && !returns_plain_value(context, &name, &args);
+            if args.len() > arity && !may_return_function {
+                let extra = TextRange::new(
+                    args[arity].text_range().start(),
+                    args[args.len() - 1].text_range().end(),
+                );
+                diagnostics.push(Diagnostic {
+                    range: utils::range(context.code, extra),
+                    severity: Some(DiagnosticSeverity::Error),
+                    message: format!(
+                        "`{}` takes {} argument{}, but is given {}",
+                        head.text(),
+                        arity,
+                        if arity == 1 { "" } else { "s" },
+                        args.len()
+                    ),
+                    ..Diagnostic::default()
+                });
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lookup::LSPDetails;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_builtin_arguments() {
+        let mut builtins = HashMap::new();
+        for (name, args) in &[
+            ("elemAt", 2),
+            ("length", 1),
+            ("map", 2),
+            ("attrNames", 1),
+            ("lessThan", 2),
+        ] {
+            builtins.insert(
+                String::from(*name),
+                LSPDetails {
+                    datatype: utils::Datatype::Lambda,
+                    var: None,
+                    documentation: None,
+                    deprecated: false,
+                    params: None,
+                    args: Some(vec![String::from("x"); *args]),
+                },
+            );
+        }
+        let code = "[ (builtins.length \"abc\") (builtins.attrNames [ ]) (map (x: x) [ ] 1 2) (builtins.elemAt [ ] 0 1) (builtins.elemAt [ f ] 0 1) (builtins.length ([ ])) (builtins.lessThan \"a\" ./b) ]";
+        let context = CheckContext::for_test(code, None, Some(builtins));
+        let mut diagnostics = Vec::new();
+        builtin_arguments(&context, &mut diagnostics);
+        assert_eq!(
+            vec![
+                (
+                    String::from("\"abc\""),
+                    String::from("`builtins.length` expects a list, but this is a string")
+                ),
+                (
+                    String::from("[ ]"),
+                    String::from(
+                        "`builtins.attrNames` expects an attribute set, but this is a list"
+                    )
+                ),
+                (
+                    String::from("1 2"),
+                    String::from("`map` takes 2 arguments, but is given 4")
+                ),
+                (
+                    String::from("1"),
+                    String::from("`builtins.elemAt` takes 2 arguments, but is given 3")
+                ),
+            ],
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| {
+                    let start = utils::lookup_pos(code, diagnostic.range.start).unwrap();
+                    let end = utils::lookup_pos(code, diagnostic.range.end).unwrap();
+                    (code[start..end].to_owned(), diagnostic.message)
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+}
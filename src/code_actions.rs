@@ -0,0 +1,404 @@
+use crate::{diagnostics, utils, App};
+use lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, Diagnostic, TextEdit, Url,
+    WorkspaceEdit,
+};
+use rnix::{
+    parser::{ParseError, AST},
+    types::*,
+    NodeOrToken, SyntaxElement, SyntaxKind, SyntaxNode, TextRange, TextSize,
+};
+use std::{collections::HashMap, convert::TryFrom, rc::Rc};
+
+/// Everything a code action provider gets to look at
+pub struct ActionContext<'a> {
+    pub uri: &'a Url,
+    pub code: &'a str,
+    pub ast: &'a AST,
+    /// The selection the client asked for actions on
+    pub range: TextRange,
+    /// The diagnostics the client shows within the selection
+    pub diagnostics: &'a [Diagnostic],
+}
+
+impl<'a> ActionContext<'a> {
+    /// Whether `range` overlaps or touches the selection
+    pub fn touches(&self, range: TextRange) -> bool {
+        range.start() <= self.range.end() && self.range.start() <= range.end()
+    }
+    /// The node that covers the whole selection
+    pub fn covering_node(&self) -> SyntaxNode {
+        match self.ast.node().covering_element(self.range) {
+            NodeOrToken::Node(node) => node,
+            NodeOrToken::Token(token) => token.parent(),
+        }
+    }
+    /// Looks up the diagnostic the client sent for a range, so that a quick
+    /// fix can say which diagnostic it fixes.
+    pub fn diagnostic(&self, range: TextRange, message: &str) -> Option<Diagnostic> {
+        let range = utils::range(self.code, range);
+        self.diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.range == range && diagnostic.message == message)
+            .cloned()
+    }
+    pub fn edit(&self, range: TextRange, new_text: &str) -> TextEdit {
+        TextEdit {
+            range: utils::range(self.code, range),
+            new_text: new_text.into(),
+        }
+    }
+    /// Builds a code action that applies `edits` to the current file
+    pub fn action(
+        &self,
+        title: String,
+        kind: CodeActionKind,
+        edits: Vec<TextEdit>,
+        diagnostic: Option<Diagnostic>,
+    ) -> CodeAction {
+        let mut changes = HashMap::new();
+        changes.insert(self.uri.clone(), edits);
+        CodeAction {
+            title,
+            kind: Some(kind),
+            diagnostics: diagnostic.map(|diagnostic| vec![diagnostic]),
+            edit: Some(WorkspaceEdit {
+                changes: Some(changes),
+                ..WorkspaceEdit::default()
+            }),
+            command: None,
+            is_preferred: None,
+        }
+    }
+}
+
+/// A source of code actions. Quick fixes go through the diagnostics in the
+/// selection, refactorings look at the syntax under it.
+type Provider = fn(&ActionContext, &mut Vec<CodeAction>) -> Option<()>;
+
+const PROVIDERS: &[Provider] = &[
+    parse_error_fixes,
+    remove_unused,
+    rewrite_legacy,
+    expand_dotted_key,
+];
+
+impl App {
+    pub fn code_actions(&self, params: &CodeActionParams) -> Option<Vec<CodeActionOrCommand>> {
+        let uri = &params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let start = utils::lookup_pos(code, params.range.start)?;
+        let end = utils::lookup_pos(code, params.range.end)?;
+        let context = ActionContext {
+            uri,
+            code,
+            ast,
+            range: TextRange::new(TextSize::try_from(start).ok()?, TextSize::try_from(end).ok()?),
+            diagnostics: &params.context.diagnostics,
+        };
+
+        let mut actions = Vec::new();
+        for provider in PROVIDERS {
+            provider(&context, &mut actions);
+        }
+        Some(actions.into_iter().map(CodeActionOrCommand::CodeAction).collect())
+    }
+}
+
+fn parse_error_fixes(context: &ActionContext, actions: &mut Vec<CodeAction>) -> Option<()> {
+    for err in context.ast.errors() {
+        let range = utils::error_range(&context.ast.node(), &err);
+        if !context.touches(range) {
+            continue;
+        }
+        let diagnostic = context.diagnostic(range, &err.to_string());
+        match &err {
+            ParseError::UnexpectedWanted(_, _, wanted) | ParseError::UnexpectedEOFWanted(wanted) => {
+                let at = end_of_previous_token(&context.ast.node(), range.start());
+                let delimiters = [
+                    (SyntaxKind::TOKEN_SEMICOLON, ";"),
+                    (SyntaxKind::TOKEN_CURLY_B_CLOSE, "}"),
+                    (SyntaxKind::TOKEN_SQUARE_B_CLOSE, "]"),
+                    (SyntaxKind::TOKEN_PAREN_CLOSE, ")"),
+                ];
+                for (kind, text) in delimiters.iter() {
+                    if wanted.contains(kind) {
+                        actions.push(context.action(
+                            format!("Insert missing `{}`", text),
+                            CodeActionKind::QUICKFIX,
+                            vec![context.edit(TextRange::empty(at), text)],
+                            diagnostic.clone(),
+                        ));
+                    }
+                }
+            }
+            ParseError::UnexpectedDoubleBind(_) => {
+                double_bind_fixes(context, range, diagnostic, actions);
+            }
+            _ => (),
+        }
+    }
+    Some(())
+}
+
+/// The end of the last token before `offset` that isn't whitespace or a
+/// comment, which is where a forgotten delimiter belongs.
+fn end_of_previous_token(root: &SyntaxNode, offset: TextSize) -> TextSize {
+    let mut token = root.token_at_offset(offset).left_biased();
+    while let Some(current) = token {
+        match current.kind() {
+            SyntaxKind::TOKEN_WHITESPACE | SyntaxKind::TOKEN_COMMENT => token = current.prev_token(),
+            _ => return current.text_range().end(),
+        }
+    }
+    offset
+}
+
+/// `a@{ ... }@b` binds the arguments twice. Either drop `@b`, or drop it
+/// and make everything that used `b` use `a` instead.
+fn double_bind_fixes(
+    context: &ActionContext,
+    range: TextRange,
+    diagnostic: Option<Diagnostic>,
+    actions: &mut Vec<CodeAction>,
+) -> Option<()> {
+    let error = context.ast.node().descendants().find(|node| {
+        node.kind() == SyntaxKind::NODE_ERROR && range.contains_range(node.text_range())
+    })?;
+    let second = error.descendants().find_map(Ident::cast)?;
+    let pattern = error.ancestors().find_map(Pattern::cast)?;
+    let first = pattern.at()?;
+    let lambda = pattern.node().parent().and_then(Lambda::cast)?;
+
+    let remove = context.edit(error.text_range(), "");
+    let mut merge = vec![remove.clone()];
+    if let Some(body) = lambda.body() {
+        let file = Rc::new(context.uri.clone());
+        for ident in body.descendants().filter_map(Ident::cast) {
+            let unbound = utils::scope_for(&file, ident.node().clone())
+                .map_or(true, |scope| !scope.contains_key(second.as_str()));
+            if ident.as_str() == second.as_str() && utils::is_variable_use(&ident) && unbound {
+                merge.push(context.edit(ident.node().text_range(), first.as_str()));
+            }
+        }
+    }
+
+    actions.push(context.action(
+        format!("Remove duplicate binding `@{}`", second.as_str()),
+        CodeActionKind::QUICKFIX,
+        vec![remove],
+        diagnostic.clone(),
+    ));
+    actions.push(context.action(
+        format!("Merge `{}` into `{}`", second.as_str(), first.as_str()),
+        CodeActionKind::QUICKFIX,
+        merge,
+        diagnostic,
+    ));
+    Some(())
+}
+
+/// Deletes bindings that nothing refers to
+fn remove_unused(context: &ActionContext, actions: &mut Vec<CodeAction>) -> Option<()> {
+    let file = Rc::new(context.uri.clone());
+    for unused in diagnostics::unused_bindings(&file, &context.ast.node()) {
+        let ident = unused.ident.node();
+        if !context.touches(ident.text_range()) {
+            continue;
+        }
+        let what = unused.describe();
+        // The last name of an `inherit` takes the whole `inherit` with it
+        let binding = unused.binding;
+        let binding = match binding.parent() {
+            Some(inherit)
+                if inherit.kind() == SyntaxKind::NODE_INHERIT
+                    && Inherit::cast(inherit.clone())?.idents().count() == 1 =>
+            {
+                inherit
+            }
+            _ => binding,
+        };
+        let diagnostic = context.diagnostic(
+            ident.text_range(),
+            &format!("unused {} `{}`", what, unused.ident.as_str()),
+        );
+        actions.push(context.action(
+            format!("Remove unused {} `{}`", what, unused.ident.as_str()),
+            CodeActionKind::QUICKFIX,
+            vec![context.edit(removal_range(&binding), "")],
+            diagnostic,
+        ));
+    }
+    Some(())
+}
+
+/// Rewrites deprecated syntax into its modern equivalent
+fn rewrite_legacy(context: &ActionContext, actions: &mut Vec<CodeAction>) -> Option<()> {
+    for legacy in diagnostics::legacy_constructs(&context.ast.node()) {
+        if !context.touches(legacy.range) {
+            continue;
+        }
+        if let Some((title, new_text)) = legacy.rewrite {
+            actions.push(context.action(
+                title.into(),
+                CodeActionKind::QUICKFIX,
+                vec![context.edit(legacy.range, &new_text)],
+                context.diagnostic(legacy.range, legacy.message),
+            ));
+        }
+    }
+    Some(())
+}
+
+/// The range to delete to remove a binding cleanly: pattern entries take
+/// a neighbouring comma along, everything else the whitespace before it.
+fn removal_range(node: &SyntaxNode) -> TextRange {
+    let skip_whitespace = |mut element: Option<SyntaxElement>, forward: bool| {
+        while let Some(current) = element.clone() {
+            if current.kind() != SyntaxKind::TOKEN_WHITESPACE {
+                break;
+            }
+            element = if forward {
+                current.next_sibling_or_token()
+            } else {
+                current.prev_sibling_or_token()
+            };
+        }
+        element
+    };
+    let range = node.text_range();
+    let before = node.prev_sibling_or_token();
+
+    if node.kind() == SyntaxKind::NODE_PAT_ENTRY {
+        let after = skip_whitespace(node.next_sibling_or_token(), true);
+        if let Some(comma) = after.filter(|after| after.kind() == SyntaxKind::TOKEN_COMMA) {
+            // Eat the whitespace after the comma as well
+            let end = skip_whitespace(comma.next_sibling_or_token(), true)
+                .map_or(comma.text_range().end(), |next| next.text_range().start());
+            return TextRange::new(range.start(), end);
+        }
+        let before = skip_whitespace(before, false);
+        if let Some(comma) = before.filter(|before| before.kind() == SyntaxKind::TOKEN_COMMA) {
+            return TextRange::new(comma.text_range().start(), range.end());
+        }
+        return range;
+    }
+    match before {
+        Some(whitespace) if whitespace.kind() == SyntaxKind::TOKEN_WHITESPACE => {
+            TextRange::new(whitespace.text_range().start(), range.end())
+        }
+        _ => range,
+    }
+}
+
+/// Rewrites `a.b.c = value;` into `a = { b.c = value; };`
+fn expand_dotted_key(context: &ActionContext, actions: &mut Vec<CodeAction>) -> Option<()> {
+    let entry = context.covering_node().ancestors().find_map(KeyValue::cast)?;
+    let key = entry.key()?;
+    let value = entry.value()?;
+    let mut path = key.path();
+    let first = path.next()?;
+    let rest = path.map(|part| part.text().to_string()).collect::<Vec<_>>();
+    if rest.is_empty() {
+        return None;
+    }
+
+    let new_text = format!("{} = {{ {} = {}; }};", first.text(), rest.join("."), value.text());
+    actions.push(context.action(
+        format!("Expand `{}` into a nested set", key.node().text()),
+        CodeActionKind::REFACTOR_REWRITE,
+        vec![context.edit(entry.node().text_range(), &new_text)],
+        None,
+    ));
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions_for(code: &str, offset: u32) -> Vec<CodeAction> {
+        let ast = rnix::parse(code);
+        let uri = Url::parse("file:///default.nix").unwrap();
+        let context = ActionContext {
+            uri: &uri,
+            code,
+            ast: &ast,
+            range: TextRange::empty(TextSize::from(offset)),
+            diagnostics: &[],
+        };
+        let mut actions = Vec::new();
+        for provider in PROVIDERS {
+            provider(&context, &mut actions);
+        }
+        actions
+    }
+
+    #[test]
+    fn test_insert_missing_semicolon() {
+        let actions = actions_for("{ a = 1; b = 2 }", 15);
+        assert_eq!("Insert missing `;`", actions[0].title);
+        let edits = &actions[0].edit.as_ref().unwrap().changes.as_ref().unwrap();
+        let edit = &edits.values().next().unwrap()[0];
+        assert_eq!(14, edit.range.start.character);
+        assert_eq!(";", edit.new_text);
+    }
+
+    #[test]
+    fn test_remove_unused() {
+        let code = "{ a, b, c }: let\n  d = a;\n  e = c;\nin d";
+        let apply = |offset: usize| {
+            let actions = actions_for(code, offset as u32);
+            let edits = actions[0].edit.as_ref().unwrap().changes.as_ref().unwrap();
+            let edit = &edits.values().next().unwrap()[0];
+            let start = utils::lookup_pos(code, edit.range.start).unwrap();
+            let end = utils::lookup_pos(code, edit.range.end).unwrap();
+            (
+                actions[0].title.clone(),
+                format!("{}{}{}", &code[..start], edit.new_text, &code[end..]),
+            )
+        };
+        assert_eq!(
+            (
+                String::from("Remove unused argument `b`"),
+                String::from("{ a, c }: let\n  d = a;\n  e = c;\nin d")
+            ),
+            apply(5)
+        );
+        assert_eq!(
+            (
+                String::from("Remove unused variable `e`"),
+                String::from("{ a, b, c }: let\n  d = a;\nin d")
+            ),
+            apply(code.find("e =").unwrap())
+        );
+
+        let code = "(rec { a = 1; b = a; c = 2; }).b";
+        let actions = actions_for(code, code.find("c =").unwrap() as u32);
+        assert_eq!("Remove unused attribute `c`", actions[0].title);
+    }
+
+    #[test]
+    fn test_rewrite_legacy() {
+        let code = "{ or = 1; src = http://example.org; }";
+        let rewrite = |offset: usize| {
+            let actions = actions_for(code, offset as u32);
+            let action = actions.iter().find(|action| action.title.starts_with("Quote")).unwrap();
+            let edits = action.edit.as_ref().unwrap().changes.as_ref().unwrap();
+            (action.title.clone(), edits.values().next().unwrap()[0].new_text.clone())
+        };
+        assert_eq!((String::from("Quote `or`"), String::from("\"or\"")), rewrite(2));
+        assert_eq!(
+            (String::from("Quote URL"), String::from("\"http://example.org\"")),
+            rewrite(code.find("http").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_expand_dotted_key() {
+        let actions = actions_for("{ a.b.c = 1; }", 3);
+        assert_eq!(1, actions.len());
+        let edits = &actions[0].edit.as_ref().unwrap().changes.as_ref().unwrap();
+        assert_eq!("a = { b.c = 1; };", edits.values().next().unwrap()[0].new_text);
+    }
+}
@@ -1,34 +1,77 @@
-use crate::{utils, App};
+use crate::{
+    lookup::{self, LSPDetails, GLOBAL_BUILTINS},
+    utils::{self, Datatype},
+    App,
+};
 use itertools::Itertools;
+use log::warn;
+use lsp_server::{Message, RequestId, Response};
 use lsp_types::{
-    CompletionItem, CompletionList, CompletionResponse, CompletionTextEdit, Documentation, Range,
-    TextDocumentPositionParams, TextEdit,
+    CompletionItem, CompletionItemKind, CompletionItemTag, CompletionList, CompletionResponse,
+    CompletionTextEdit, Documentation, Range, TextDocumentPositionParams, TextEdit, Url,
 };
 use manix::{DocEntry, DocSource};
 use rnix::{
-    types::{ParsedType, TokenWrapper, TypedNode},
-    NixLanguage, SyntaxKind, SyntaxNode, TextSize,
+    parser::AST,
+    types::{
+        Apply, AttrSet, EntryHolder, Ident, Key, KeyValue, Lambda, ParsedType, PatEntry, Pattern,
+        Select, TokenWrapper, TypedNode, Value, With,
+    },
+    value::{Anchor, Value as ParsedValue},
+    NixLanguage, SyntaxKind, SyntaxNode, TextRange, TextSize,
+};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    fs,
+    io::Read,
+    iter,
+    process::{Command, Stdio},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
-use std::convert::TryFrom;
+
+/// A package's description and version
+type PackageMeta = (Option<String>, Option<String>);
+
+/// What evaluating nixpkgs found for each package attribute path. Failures
+/// are kept as `None`, so that they aren't tried again for every item.
+pub type PackageMetaCache = Arc<Mutex<HashMap<Vec<String>, Option<PackageMeta>>>>;
+
+/// How long evaluating nixpkgs for a package may take
+const PACKAGE_META_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many bindings to follow when looking for what a name stands for.
+/// Bindings may refer to themselves, so give up eventually.
+const MAX_BINDING_STEPS: usize = 32;
 
 impl App {
+    /// Completions for names in scope at the cursor, along with the
+    /// attribute path in front of the name being completed
     fn scope_completions(
         &mut self,
         params: &TextDocumentPositionParams,
-    ) -> Option<Vec<CompletionItem>> {
+    ) -> Option<(Vec<String>, Vec<CompletionItem>)> {
         let (ast, content) = self.files.get(&params.text_document.uri)?;
         let offset = utils::lookup_pos(content, params.position)?;
         let root_node = ast.node();
+        let path = utils::ident_at(&root_node, offset)?.path;
 
         let (name, scope, _) =
             self.scope_for_ident(params.text_document.uri.clone(), &root_node, offset)?;
         let (_, content) = self.files.get(&params.text_document.uri)?;
 
         let scope_completions = scope
-            .keys()
-            .filter(|var| var.starts_with(&name.as_str()))
-            .map(|var| CompletionItem {
+            .iter()
+            .filter(|(var, _)| var.starts_with(&name.as_str()))
+            .map(|(var, details)| CompletionItem {
                 label: var.clone(),
+                kind: Some(completion_kind(details)),
+                detail: Some(details.render_detail()),
+                documentation: details.documentation.clone().map(Documentation::String),
                 text_edit: Some(CompletionTextEdit::Edit(TextEdit {
                     range: utils::range(content, name.node().text_range()),
                     new_text: var.clone(),
@@ -36,15 +79,347 @@ impl App {
                 ..CompletionItem::default()
             })
             .collect_vec();
-        Some(scope_completions)
+        Some((path, scope_completions))
+    }
+
+    /// Completes the entries of the directory a path literal is being
+    /// written in, for paths starting with `./`, `../` or `~/`
+    fn path_completions(&self, params: &TextDocumentPositionParams) -> Option<Vec<CompletionItem>> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position)?;
+        complete_path(&params.text_document.uri, ast, content, offset)
     }
 
+    /// Completes the attributes a function with a pattern argument takes,
+    /// inside the set it is called with, like `f { | }`
+    fn argument_completions(
+        &mut self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position)?;
+        let cursor = TextSize::try_from(offset).ok()?;
+        let token = ast.node().token_at_offset(cursor).left_biased()?;
+
+        let (set, partial, range) = if token.kind() == SyntaxKind::TOKEN_IDENT {
+            let key = token.parent().parent().and_then(Key::cast)?;
+            // Only the first segment of a key names an argument
+            if key.path().next()? != token.parent() {
+                return None;
+            }
+            let typed = usize::from(cursor - token.text_range().start());
+            (
+                key.node().parent()?.parent()?,
+                token.text()[..typed].to_owned(),
+                token.text_range(),
+            )
+        } else {
+            let parent = token.parent();
+            let set = match parent.kind() {
+                SyntaxKind::NODE_ATTR_SET => parent,
+                SyntaxKind::NODE_KEY_VALUE | SyntaxKind::NODE_INHERIT
+                    if token.kind() == SyntaxKind::TOKEN_SEMICOLON =>
+                {
+                    parent.parent()?
+                }
+                _ => return None,
+            };
+            (set, String::new(), TextRange::empty(cursor))
+        };
+        let range = utils::range(content, range);
+        let set = AttrSet::cast(set)?;
+        let apply = set.node().parent().and_then(Apply::cast)?;
+        if apply.value()? != *set.node() {
+            return None;
+        }
+
+        let mut supplied = set
+            .entries()
+            .filter(|entry| !entry.node().text_range().contains_inclusive(cursor))
+            .filter_map(|entry| Some(entry.key()?.path().next()?.text().to_string()))
+            .collect::<HashSet<_>>();
+        supplied.extend(
+            set.inherits()
+                .flat_map(|inherit| inherit.idents())
+                .map(|ident| ident.as_str().to_owned()),
+        );
+
+        let mut file = Rc::new(params.text_document.uri.clone());
+        let lambda = called_lambda(&mut self.files, &mut file, apply.lambda()?)?;
+        let pattern = lambda.arg().and_then(Pattern::cast)?;
+        let completions = pattern
+            .entries()
+            .filter_map(|entry| {
+                let name = entry.name()?.as_str().to_owned();
+                if supplied.contains(&name) || !name.starts_with(&partial) {
+                    return None;
+                }
+                let default = entry.default();
+                Some(CompletionItem {
+                    label: name.clone(),
+                    kind: Some(CompletionItemKind::Field),
+                    detail: Some(match &default {
+                        Some(default) => format!("? {}", default.text()),
+                        None => String::from("required"),
+                    }),
+                    // Required arguments first
+                    sort_text: Some(format!("{}{}", u8::from(default.is_some()), name)),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: name,
+                    })),
+                    ..CompletionItem::default()
+                })
+            })
+            .collect_vec();
+        // With no argument left to suggest, other completions get their turn
+        if completions.is_empty() {
+            None
+        } else {
+            Some(completions)
+        }
+    }
+
+    /// Completes the key being written in a module with the next segment of
+    /// NixOS, Home Manager and nix-darwin option paths
     fn manix_options_completions(
         &self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        // TODO implement this
-        None
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position)?;
+        let root_node = ast.node();
+        let cursor = TextSize::try_from(offset).ok()?;
+        let token = root_node.token_at_offset(cursor).left_biased()?;
+
+        let (set, written, partial, range) = match token.parent().ancestors().find_map(Key::cast) {
+            Some(key) => {
+                let set = key
+                    .node()
+                    .parent()
+                    .and_then(KeyValue::cast)?
+                    .node()
+                    .parent()?;
+                // Either a segment is being typed, or the cursor is right after a dot
+                let (partial, range) = if token.kind() == SyntaxKind::TOKEN_IDENT {
+                    let typed = TextRange::new(token.text_range().start(), cursor);
+                    (
+                        token.text()[..usize::from(typed.len())].to_owned(),
+                        token.text_range(),
+                    )
+                } else {
+                    (String::new(), TextRange::empty(cursor))
+                };
+                let written = key
+                    .path()
+                    .filter(|segment| segment.text_range().end() <= range.start())
+                    .map(|segment| option_segment(&segment.text().to_string()))
+                    .collect_vec();
+                (set, written, partial, range)
+            }
+            None => (
+                token.parent(),
+                Vec::new(),
+                String::new(),
+                TextRange::empty(cursor),
+            ),
+        };
+        if set.kind() != SyntaxKind::NODE_ATTR_SET {
+            return None;
+        }
+        let module = module_set(&root_node)?;
+        let mut namespace = option_path(&module, &set)?;
+        namespace.extend(written);
+        let queries = option_queries(&namespace, &partial)?;
+
+        // Names like `<name>` in option paths stand for any attribute, so when
+        // nothing matches what was written literally, search more broadly
+        let in_namespace = |entry: &DocEntry| {
+            let name = entry.name();
+            let segments = name.split('.').collect_vec();
+            segments.len() > namespace.len()
+                && segments
+                    .iter()
+                    .zip(&namespace)
+                    .all(|(option, written)| option == written || is_placeholder(option))
+                && segments[namespace.len()].starts_with(&partial)
+        };
+        let options = queries
+            .into_iter()
+            .map(|query| {
+                self.manix_options
+                    .search(&manix::Lowercase(query.as_bytes()))
+                    .into_iter()
+                    .filter(|entry| in_namespace(entry))
+                    .collect_vec()
+            })
+            .find(|options| !options.is_empty())?;
+
+        // Each segment either names an option, or a set of further options
+        let mut segments = BTreeMap::new();
+        for entry in &options {
+            let name = entry.name();
+            let mut rest = name.split('.').skip(namespace.len());
+            let segment = rest.next()?.to_owned();
+            if is_placeholder(&segment) {
+                continue;
+            }
+            if rest.next().is_none() {
+                segments.insert(segment, Some(entry));
+            } else {
+                segments.entry(segment).or_insert(None);
+            }
+        }
+
+        Some(
+            segments
+                .into_iter()
+                .map(|(segment, entry)| CompletionItem {
+                    label: segment.clone(),
+                    kind: Some(if entry.is_some() {
+                        CompletionItemKind::Property
+                    } else {
+                        CompletionItemKind::Module
+                    }),
+                    documentation: entry.map(|entry| Documentation::String(entry.pretty_printed())),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range: utils::range(content, range),
+                        new_text: segment,
+                    })),
+                    ..CompletionItem::default()
+                })
+                .collect_vec(),
+        )
+    }
+
+    /// Completes attribute paths into nixpkgs, after `pkgs.` or anywhere in
+    /// the body of a `with pkgs;`, one segment at a time. Other names for
+    /// nixpkgs work too, as far as `is_nixpkgs` can tell.
+    fn package_completions(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position)?;
+        let cursor = TextSize::try_from(offset).ok()?;
+        let token = ast.node().token_at_offset(cursor).left_biased()?;
+
+        // The set being selected from, if any
+        let (set, partial, range) = match token.kind() {
+            SyntaxKind::TOKEN_IDENT => {
+                let ident = Ident::cast(token.parent())?;
+                let set = match ident.node().parent().and_then(Select::cast) {
+                    Some(select)
+                        if select.index().map_or(false, |index| index == *ident.node()) =>
+                    {
+                        Some(select.set()?)
+                    }
+                    _ if utils::is_variable_use(&ident) => None,
+                    _ => return None,
+                };
+                let typed = usize::from(cursor - token.text_range().start());
+                (set, token.text()[..typed].to_owned(), token.text_range())
+            }
+            SyntaxKind::TOKEN_DOT => {
+                let select = Select::cast(token.parent())?;
+                (Some(select.set()?), String::new(), TextRange::empty(cursor))
+            }
+            _ => return None,
+        };
+        let written = match &set {
+            Some(set) => attr_path(set)?,
+            None => Vec::new(),
+        };
+
+        let file = Rc::new(params.text_document.uri.clone());
+        let mut root = set.clone();
+        while let Some(select) = root.clone().and_then(Select::cast) {
+            root = select.set();
+        }
+        let path = match root {
+            Some(root) if is_nixpkgs(&file, root) => written[1..].to_vec(),
+            _ => {
+                // Anything bound locally wins over what `with` brings into scope
+                let name = written
+                    .first()
+                    .map_or(token.text().as_str(), String::as_str);
+                let bound = utils::scope_for(&file, token.parent())
+                    .map_or(false, |scope| scope.contains_key(name));
+                let in_with_pkgs = token
+                    .parent()
+                    .ancestors()
+                    .filter_map(With::cast)
+                    .any(|with| {
+                        with.namespace()
+                            .map_or(false, |namespace| is_nixpkgs(&file, namespace))
+                    });
+                if bound || !in_with_pkgs {
+                    return None;
+                }
+                written
+            }
+        };
+
+        let query = iter::once("pkgs")
+            .chain(path.iter().map(String::as_str))
+            .chain(iter::once(partial.as_str()))
+            .join(".");
+        // Whether each segment is a package, rather than a set of packages
+        let mut segments = BTreeMap::new();
+        for entry in self
+            .manix_values
+            .search(&manix::Lowercase(query.as_bytes()))
+        {
+            let name = match entry {
+                DocEntry::NixpkgsTreeDoc(name) => name,
+                _ => continue,
+            };
+            let mut rest = name.split('.').skip(1);
+            if !rest
+                .by_ref()
+                .take(path.len())
+                .eq(path.iter().map(String::as_str))
+            {
+                continue;
+            }
+            let segment = match rest.next() {
+                Some(segment) if segment.starts_with(&partial) => segment.to_owned(),
+                _ => continue,
+            };
+            if rest.next().is_none() {
+                segments.insert(segment, true);
+            } else {
+                segments.entry(segment).or_insert(false);
+            }
+        }
+
+        Some(
+            segments
+                .into_iter()
+                .map(|(segment, package)| CompletionItem {
+                    label: segment.clone(),
+                    kind: Some(if package {
+                        CompletionItemKind::Value
+                    } else {
+                        CompletionItemKind::Module
+                    }),
+                    // Descriptions and versions need nixpkgs evaluated, so they are
+                    // only looked up once the client resolves the item
+                    data: if package {
+                        let mut attr_path = path.clone();
+                        attr_path.push(segment.clone());
+                        Some(json!({ "package": attr_path }))
+                    } else {
+                        None
+                    },
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range: utils::range(content, range),
+                        new_text: segment,
+                    })),
+                    ..CompletionItem::default()
+                })
+                .collect_vec(),
+        )
     }
 
     fn manix_value_completions(
@@ -115,14 +490,39 @@ impl App {
         &mut self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        // let scope_completions = self.scope_completions(params)?;
-        let mut manix_value_completions = self.manix_value_completions(params).unwrap_or_default();
+        if let Some(paths) = self.path_completions(params) {
+            return Some(paths);
+        }
+        // Only argument names make sense as keys of the set a function is called with
+        if let Some(arguments) = self.argument_completions(params) {
+            return Some(arguments);
+        }
+
+        let (namespace, completions) = self.scope_completions(params).unwrap_or_default();
+        // Packages already cover what a documentation search would find there
+        let manix_value_completions = self
+            .package_completions(params)
+            .or_else(|| self.manix_value_completions(params))
+            .unwrap_or_default();
         let mut manix_options_completions =
             self.manix_options_completions(params).unwrap_or_default();
-        let mut completions = Vec::new();
-        completions.append(&mut manix_value_completions);
+
+        let mut completions = merge_completions(&namespace, completions, manix_value_completions);
         completions.append(&mut manix_options_completions);
 
+        let builtins = self.builtins();
+        for item in &mut completions {
+            let name = match item.label.strip_prefix("builtins.") {
+                Some(name) => name,
+                None if GLOBAL_BUILTINS.contains(&item.label.as_str()) => &item.label,
+                None => continue,
+            };
+            if builtins.get(name).map_or(false, |details| details.deprecated) {
+                item.deprecated = Some(true);
+                item.tags = Some(vec![CompletionItemTag::Deprecated]);
+            }
+        }
+
         Some(completions)
     }
 
@@ -131,8 +531,6 @@ impl App {
         current_ns: Vec<String>,
         search_results: Vec<DocEntry>,
     ) -> (Vec<String>, Vec<NamespaceCompletionResult>) {
-        // TODO handle things like `with pkgs;`
-
         let query_ns_iter = current_ns.iter();
         let longest_match = search_results
             .iter()
@@ -178,6 +576,462 @@ impl App {
     }
 }
 
+/// Completes the path literal being written at `offset` in the file at `uri`
+fn complete_path(
+    uri: &Url,
+    ast: &AST,
+    content: &str,
+    offset: usize,
+) -> Option<Vec<CompletionItem>> {
+    let cursor = TextSize::try_from(offset).ok()?;
+    let token = ast.node().token_at_offset(cursor).left_biased()?;
+    let in_string = token
+        .parent()
+        .ancestors()
+        .any(|node| node.kind() == SyntaxKind::NODE_STRING);
+    if token.kind() == SyntaxKind::TOKEN_COMMENT || in_string {
+        return None;
+    }
+
+    // A path that's still being written may not lex as one yet, so look
+    // at the text instead
+    let before = &content[..offset];
+    let start = before
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !(c.is_ascii_alphanumeric() || "._-+/~".contains(c)))
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let typed = &before[start..];
+    let (anchor, relative) = if let Some(home) = typed.strip_prefix("~/") {
+        (Anchor::Home, home)
+    } else if typed.starts_with("./") || typed.starts_with("../") {
+        (Anchor::Relative, typed)
+    } else {
+        return None;
+    };
+    let (dir, name) = relative.split_at(relative.rfind('/').map_or(0, |i| i + 1));
+    let dir = utils::resolve_path(uri, anchor, dir)?;
+
+    let file = Rc::new(uri.clone());
+    let import = token
+        .parent()
+        .ancestors()
+        .find(|node| node.kind() == SyntaxKind::NODE_VALUE)
+        .map_or(false, |node| lookup::is_import_target(&file, &node));
+    let range = utils::range(content, TextRange::new(cursor - TextSize::of(name), cursor));
+
+    let mut completions = Vec::new();
+    for entry in fs::read_dir(dir).ok()?.filter_map(Result::ok) {
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        // Hidden files only show up once a dot is typed
+        if !entry_name.starts_with(name) || (entry_name.starts_with('.') && !name.starts_with('.'))
+        {
+            continue;
+        }
+        let path = entry.path();
+        let is_dir = path.is_dir();
+        let importable = if is_dir {
+            path.join("default.nix").is_file()
+        } else {
+            path.extension()
+                .map_or(false, |extension| extension == "nix")
+        };
+        let label = if is_dir {
+            format!("{}/", entry_name)
+        } else {
+            entry_name
+        };
+        completions.push(CompletionItem {
+            label: label.clone(),
+            kind: Some(if is_dir {
+                CompletionItemKind::Folder
+            } else {
+                CompletionItemKind::File
+            }),
+            // What can be imported comes first where something is imported
+            sort_text: Some(format!("{}{}", u8::from(!(import && importable)), label)),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: label,
+            })),
+            ..CompletionItem::default()
+        });
+    }
+    Some(completions)
+}
+
+/// Follows a called expression to the lambda it evaluates to, through
+/// local bindings and `import` or `callPackage` of other files
+fn called_lambda(
+    files: &mut HashMap<Url, (AST, String)>,
+    file: &mut Rc<Url>,
+    mut node: SyntaxNode,
+) -> Option<Lambda> {
+    for _ in 0..MAX_BINDING_STEPS {
+        node = match ParsedType::try_from(node).ok()? {
+            ParsedType::Lambda(lambda) => return Some(lambda),
+            ParsedType::Paren(paren) => paren.inner()?,
+            ParsedType::Ident(ident) => {
+                utils::scope_for(file, ident.node().clone())?
+                    .remove(ident.as_str())?
+                    .value?
+            }
+            ParsedType::Apply(apply) => {
+                let name = match ParsedType::try_from(apply.lambda()?).ok()? {
+                    ParsedType::Ident(ident) => ident.as_str().to_owned(),
+                    ParsedType::Select(select) => Ident::cast(select.index()?)?.as_str().to_owned(),
+                    _ => return None,
+                };
+                if name != "import" && name != "callPackage" {
+                    return None;
+                }
+                let path = match Value::cast(apply.value()?)?.to_value().ok()? {
+                    ParsedValue::Path(_, path) => path,
+                    _ => return None,
+                };
+                lookup::imported_root(files, file, &path)?
+            }
+            _ => return None,
+        };
+    }
+    None
+}
+
+/// The names in `a.b.c`, if it is nothing but names
+fn attr_path(node: &SyntaxNode) -> Option<Vec<String>> {
+    match ParsedType::try_from(node.clone()).ok()? {
+        ParsedType::Ident(ident) => Some(vec![ident.as_str().to_owned()]),
+        ParsedType::Select(select) => {
+            let mut path = attr_path(&select.set()?)?;
+            path.push(Ident::cast(select.index()?)?.as_str().to_owned());
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `node` evaluates to the nixpkgs package set, as far as can be
+/// told without evaluating anything: `pkgs` by convention,
+/// `import <nixpkgs> { }`, or a name bound to one of those.
+fn is_nixpkgs(file: &Rc<Url>, mut node: SyntaxNode) -> bool {
+    for _ in 0..MAX_BINDING_STEPS {
+        node = match ParsedType::try_from(node) {
+            Ok(ParsedType::Paren(paren)) => match paren.inner() {
+                Some(inner) => inner,
+                None => return false,
+            },
+            Ok(ParsedType::Ident(ident)) => {
+                if ident.as_str() == "pkgs" {
+                    return true;
+                }
+                let var = utils::scope_for(file, ident.node().clone())
+                    .and_then(|mut scope| scope.remove(ident.as_str()));
+                // Function arguments can only be told by their default
+                let value = var.and_then(|var| {
+                    var.value
+                        .or_else(|| PatEntry::cast(var.key.parent()?)?.default())
+                });
+                match value {
+                    Some(value) => value,
+                    None => return false,
+                }
+            }
+            Ok(ParsedType::Apply(apply)) => {
+                let imported = apply.lambda().and_then(Apply::cast);
+                return imported.map_or(false, |imported| {
+                    let import = imported
+                        .lambda()
+                        .and_then(|lambda| attr_path(&lambda))
+                        .map_or(false, |path| path.last().map(String::as_str) == Some("import"));
+                    let nixpkgs = imported
+                        .value()
+                        .and_then(Value::cast)
+                        .and_then(|value| value.to_value().ok())
+                        .map_or(false, |value| match value {
+                            ParsedValue::Path(Anchor::Store, path) => path == "nixpkgs",
+                            _ => false,
+                        });
+                    import && nixpkgs
+                });
+            }
+            _ => return false,
+        };
+    }
+    false
+}
+
+impl App {
+    /// Answers `completionItem/resolve`. Descriptions and versions of
+    /// packages take evaluating nixpkgs, which is too slow to do for every
+    /// item up front, and even for one item too slow to keep other requests
+    /// waiting. So that happens on a thread of its own, which replies once
+    /// it's done.
+    pub fn resolve_completion(&mut self, id: RequestId, item: CompletionItem) {
+        let path = match package_path(&item) {
+            Some(path) => path,
+            None => {
+                self.reply(Response::new_ok(id, item));
+                return;
+            }
+        };
+        let cached = self
+            .package_meta
+            .lock()
+            .ok()
+            .and_then(|cache| cache.get(&path).cloned());
+        if let Some(meta) = cached {
+            self.reply(Response::new_ok(id, with_meta(item, meta)));
+            return;
+        }
+
+        let cache = Arc::clone(&self.package_meta);
+        let sender = self.conn.sender.clone();
+        let nixpkgs = self.config.nixpkgs();
+        thread::spawn(move || {
+            let meta = package_meta(&nixpkgs, &path);
+            if let Ok(mut cache) = cache.lock() {
+                cache.insert(path, meta.clone());
+            }
+            let response = Response::new_ok(id, with_meta(item, meta));
+            let _ = sender.send(Message::Response(response));
+        });
+    }
+}
+
+/// The attribute path of the package a completion item is for
+fn package_path(item: &CompletionItem) -> Option<Vec<String>> {
+    item.data
+        .as_ref()?
+        .get("package")?
+        .as_array()?
+        .iter()
+        .map(|segment| segment.as_str().map(String::from))
+        .collect()
+}
+
+fn with_meta(mut item: CompletionItem, meta: Option<PackageMeta>) -> CompletionItem {
+    if let Some((description, version)) = meta {
+        item.detail = version;
+        item.documentation = description.map(Documentation::String);
+    }
+    item
+}
+
+/// The Nix expression for the description and version of the package at
+/// `path` in the nixpkgs that `nixpkgs` points to
+fn package_meta_expr(nixpkgs: &str, path: &[String]) -> Option<String> {
+    // JSON strings are valid Nix strings, which covers names like `gtk+`
+    let attr = path
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?
+        .join(".");
+    Some(format!(
+        "let package = (import ({}) {{ }}).{}; in {{ description = package.meta.description or null; version = package.version or null; }}",
+        nixpkgs, attr
+    ))
+}
+
+/// Evaluates the description and version of a package, giving up after
+/// `PACKAGE_META_TIMEOUT`
+fn package_meta(nixpkgs: &str, path: &[String]) -> Option<PackageMeta> {
+    let expr = package_meta_expr(nixpkgs, path)?;
+    let mut child = Command::new("nix-instantiate")
+        .args(&["--eval", "--strict", "--json", "-E", &expr])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // The output is small enough to fit in the pipe, so it can wait until
+    // the process is done
+    let deadline = Instant::now() + PACKAGE_META_TIMEOUT;
+    let status = loop {
+        match child.try_wait().ok()? {
+            Some(status) => break status,
+            None if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            None => {
+                warn!("Evaluating {} timed out", path.join("."));
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    };
+    if !status.success() {
+        return None;
+    }
+    let mut output = Vec::new();
+    child.stdout.take()?.read_to_end(&mut output).ok()?;
+    let meta: serde_json::Value = serde_json::from_slice(&output).ok()?;
+    Some((
+        meta["description"].as_str().map(String::from),
+        meta["version"].as_str().map(String::from),
+    ))
+}
+
+/// Arguments that only modules take
+const MODULE_ARGUMENTS: &[&str] = &["config", "options", "pkgs", "modulesPath"];
+
+/// The attribute set a module evaluates to, if the file at `root` looks like
+/// a module: either a function taking module arguments like
+/// `{ config, lib, ... }`, or a set with `imports`, `options` or `config`.
+fn module_set(root: &SyntaxNode) -> Option<AttrSet> {
+    let expr = root.first_child()?;
+    let (mut body, takes_arguments) = match Lambda::cast(expr.clone()) {
+        Some(lambda) => {
+            let takes_arguments = lambda
+                .arg()
+                .and_then(Pattern::cast)
+                .map_or(false, |pattern| {
+                    pattern.ellipsis()
+                        && pattern.entries().any(|entry| {
+                            entry
+                                .name()
+                                .map_or(false, |name| MODULE_ARGUMENTS.contains(&name.as_str()))
+                        })
+                });
+            (lambda.body()?, takes_arguments)
+        }
+        None => (expr, false),
+    };
+    loop {
+        body = match ParsedType::try_from(body).ok()? {
+            ParsedType::Paren(paren) => paren.inner()?,
+            ParsedType::LetIn(let_in) => let_in.body()?,
+            ParsedType::With(with) => with.body()?,
+            ParsedType::AttrSet(set) => {
+                let declares = set
+                    .entries()
+                    .filter_map(|entry| entry.key()?.path().next())
+                    .any(|segment| {
+                        let name = segment.text().to_string();
+                        name == "imports" || name == "options" || name == "config"
+                    });
+                return if takes_arguments || declares {
+                    Some(set)
+                } else {
+                    None
+                };
+            }
+            _ => return None,
+        };
+    }
+}
+
+/// The option path the keys of `set` continue, if it is the set of `module`
+/// or nested in it as the value of other attributes, possibly under
+/// `mkIf`. Definitions under `config` and declarations under `options`
+/// follow the same paths.
+fn option_path(module: &AttrSet, set: &SyntaxNode) -> Option<Vec<String>> {
+    let mut path = Vec::new();
+    let mut node = set.clone();
+    while node != *module.node() {
+        let parent = node.parent()?;
+        node = match ParsedType::try_from(parent.clone()).ok()? {
+            ParsedType::KeyValue(key_value) if key_value.value()? == node => {
+                let mut segments = key_value
+                    .key()?
+                    .path()
+                    .map(|segment| option_segment(&segment.text().to_string()))
+                    .collect_vec();
+                segments.append(&mut path);
+                path = segments;
+                AttrSet::cast(parent.parent()?)?.node().clone()
+            }
+            ParsedType::Apply(apply) if apply.value()? == node => {
+                let mut head = apply.lambda()?;
+                while let Some(inner) = Apply::cast(head.clone()) {
+                    head = inner.lambda()?;
+                }
+                if attr_path(&head)?.last()? != "mkIf" {
+                    return None;
+                }
+                parent
+            }
+            ParsedType::Paren(_) => parent,
+            _ => return None,
+        };
+    }
+    if path
+        .first()
+        .map_or(false, |first| first == "config" || first == "options")
+    {
+        path.remove(0);
+    }
+    Some(path)
+}
+
+/// The searches to try for options under `namespace`, most specific first.
+/// Everything would match an empty prefix, so there are none for that.
+fn option_queries(namespace: &[String], partial: &str) -> Option<Vec<String>> {
+    if namespace.is_empty() {
+        if partial.is_empty() {
+            return None;
+        }
+        return Some(vec![partial.to_owned()]);
+    }
+    Some(
+        (1..=namespace.len())
+            .rev()
+            .map(|known| namespace[..known].join("."))
+            .collect_vec(),
+    )
+}
+
+/// An attribute name as it appears in option paths, without quotes
+fn option_segment(segment: &str) -> String {
+    segment.trim_matches('"').to_owned()
+}
+
+/// Whether a segment of an option path stands for any attribute name, like
+/// `<name>` in `services.nginx.virtualHosts.<name>.root`
+fn is_placeholder(segment: &str) -> bool {
+    segment == "*" || (segment.starts_with('<') && segment.ends_with('>'))
+}
+
+/// Puts completions of local bindings in `namespace` first, followed by
+/// those manix knows of. Local bindings shadow whatever manix knows under the
+/// same name, and come first since they are most likely what's wanted.
+fn merge_completions(
+    namespace: &[String],
+    mut local: Vec<CompletionItem>,
+    manix: Vec<CompletionItem>,
+) -> Vec<CompletionItem> {
+    let shadowed = local
+        .iter()
+        .map(|item| namespace.iter().chain(iter::once(&item.label)).join("."))
+        .collect::<HashSet<_>>();
+    for item in &mut local {
+        item.sort_text = Some(format!("0{}", item.label));
+    }
+    local.extend(
+        manix
+            .into_iter()
+            .filter(|item| !shadowed.contains(&item.label))
+            .map(|item| CompletionItem {
+                sort_text: Some(format!("1{}", item.label)),
+                ..item
+            }),
+    );
+    local
+}
+
+/// Functions are told apart from other values where the binding shows it
+fn completion_kind(details: &LSPDetails) -> CompletionItemKind {
+    let value = details.var.as_ref().and_then(|var| var.value.as_ref());
+    match details.datatype {
+        // Builtins are the only lambda entries without a binding
+        Datatype::Lambda if details.var.is_none() => CompletionItemKind::Function,
+        _ if value.map_or(false, |value| value.kind() == SyntaxKind::NODE_LAMBDA) => {
+            CompletionItemKind::Function
+        }
+        Datatype::Lambda | Datatype::Variable => CompletionItemKind::Variable,
+        Datatype::Attribute => CompletionItemKind::Field,
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum NamespaceCompletionResult {
     Set(String),
@@ -201,3 +1055,227 @@ impl NamespaceCompletionResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Position;
+    use rnix::types::Paren;
+
+    fn labelled(labels: &[&str]) -> Vec<CompletionItem> {
+        labels
+            .iter()
+            .map(|label| CompletionItem::new_simple(String::from(*label), String::new()))
+            .collect()
+    }
+
+    #[test]
+    fn test_module_set() {
+        let is_module = |code: &str| module_set(&rnix::parse(code).node()).is_some();
+        assert!(is_module(
+            "{ config, lib, ... }: { services.nginx.enable = true; }"
+        ));
+        assert!(is_module(
+            "{ pkgs, ... }: let a = 1; in { environment = { }; }"
+        ));
+        assert!(is_module("{ imports = [ ./hardware.nix ]; }"));
+        assert!(!is_module(
+            "{ stdenv }: stdenv.mkDerivation { name = \"a\"; }"
+        ));
+        assert!(!is_module("{ a = 1; }"));
+    }
+
+    #[test]
+    fn test_option_path() {
+        let code = "{ config, lib, ... }: { services.nginx = { }; config = lib.mkIf true { networking = { }; }; a = f { }; }";
+        let root = rnix::parse(code).node();
+        let module = module_set(&root).unwrap();
+        let paths = root
+            .descendants()
+            .filter(|node| node.kind() == SyntaxKind::NODE_ATTR_SET)
+            .map(|set| option_path(&module, &set))
+            .collect_vec();
+        let path = |segments: &[&str]| Some(segments.iter().map(|s| s.to_string()).collect_vec());
+        assert_eq!(
+            vec![
+                path(&[]),
+                path(&["services", "nginx"]),
+                path(&[]),
+                path(&["networking"]),
+                None,
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_option_queries() {
+        let namespace = |segments: &[&str]| segments.iter().map(|s| s.to_string()).collect_vec();
+        assert_eq!(None, option_queries(&[], ""));
+        assert_eq!(Some(vec!["serv".to_string()]), option_queries(&[], "serv"));
+        assert_eq!(
+            Some(vec!["services.nginx".to_string(), "services".to_string()]),
+            option_queries(&namespace(&["services", "nginx"]), "")
+        );
+    }
+
+    #[test]
+    fn test_is_nixpkgs() {
+        let code = "{ np ? import <nixpkgs> { } }: let nixpkgs = import <nixpkgs> { }; other = import ./other.nix { }; in [ (with pkgs; a) (with nixpkgs; a) (with import <nixpkgs> { }; a) (with np; a) (with other; a) (with lib; a) ]";
+        let root = rnix::parse(code).node();
+        let file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let found = root
+            .descendants()
+            .filter_map(With::cast)
+            .map(|with| is_nixpkgs(&file, with.namespace().unwrap()))
+            .collect_vec();
+        assert_eq!(vec![true, true, true, true, false, false], found);
+    }
+
+    #[test]
+    fn test_package_meta_expr() {
+        let path = vec![String::from("python3Packages"), String::from("gtk+")];
+        let expr = package_meta_expr("/home/user/nixpkgs", &path).unwrap();
+        assert!(expr.starts_with(
+            "let package = (import (/home/user/nixpkgs) { }).\"python3Packages\".\"gtk+\";"
+        ));
+
+        let item = CompletionItem {
+            data: Some(json!({ "package": &path })),
+            ..CompletionItem::default()
+        };
+        assert_eq!(Some(path), package_path(&item));
+        assert_eq!(None, package_path(&CompletionItem::default()));
+    }
+
+    #[test]
+    fn test_complete_path() {
+        let temp = utils::TempDir::new("complete-path");
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("project/lib")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(dir.join("project/lib/default.nix"), "{ }").unwrap();
+        fs::write(dir.join("project/lib.txt"), "").unwrap();
+        fs::write(dir.join("project/notes.txt"), "").unwrap();
+        fs::write(dir.join("project/.hidden"), "").unwrap();
+        fs::write(dir.join("shared.nix"), "{ }").unwrap();
+
+        let uri = Url::from_file_path(dir.join("project/default.nix")).unwrap();
+        let complete = |uri: &Url, code: &str| {
+            complete_path(uri, &rnix::parse(code), code, code.len()).map(|items| {
+                items
+                    .into_iter()
+                    .sorted_by(|a, b| a.sort_text.cmp(&b.sort_text))
+                    .map(|item| item.label)
+                    .collect_vec()
+            })
+        };
+        let labels = |labels: &[&str]| Some(labels.iter().map(|s| s.to_string()).collect_vec());
+
+        assert_eq!(
+            labels(&["lib.txt", "lib/", "notes.txt"]),
+            complete(&uri, "[ ./")
+        );
+        assert_eq!(
+            labels(&["other/", "project/", "shared.nix"]),
+            complete(&uri, "[ ../")
+        );
+        // What can be imported comes first where something is imported
+        assert_eq!(labels(&["lib.txt", "lib/"]), complete(&uri, "[ ./lib"));
+        assert_eq!(labels(&["lib/", "lib.txt"]), complete(&uri, "import ./lib"));
+
+        let code = "{ src = ./no; }";
+        let offset = code.find(';').unwrap();
+        let items = complete_path(&uri, &rnix::parse(code), code, offset).unwrap();
+        assert_eq!(1, items.len());
+        assert_eq!("notes.txt", items[0].label);
+        match &items[0].text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => {
+                assert_eq!(
+                    Range::new(Position::new(0, 10), Position::new(0, 12)),
+                    edit.range
+                )
+            }
+            _ => panic!("expected a text edit"),
+        }
+
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        assert_eq!(None, complete(&untitled, "import ./"));
+    }
+
+    #[test]
+    fn test_called_lambda() {
+        let mut files = HashMap::new();
+        let code = "let f = { a, b ? 1 }: a; g = f; in g { }";
+        let root = rnix::parse(code).node();
+        let mut file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let apply = root.descendants().find_map(Apply::cast).unwrap();
+        let lambda = called_lambda(&mut files, &mut file, apply.lambda().unwrap()).unwrap();
+        assert_eq!("{ a, b ? 1 }: a", lambda.node().text().to_string());
+
+        let temp = utils::TempDir::new("called-lambda");
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("package")).unwrap();
+        fs::write(
+            dir.join("package/default.nix"),
+            "{ stdenv, lib ? null }: stdenv.mkDerivation { }",
+        )
+        .unwrap();
+        fs::write(dir.join("id.nix"), "x: x").unwrap();
+
+        let code = "[ (callPackage ./package { }) (import ./id.nix 1) ]";
+        let root = rnix::parse(code).node();
+        let default = Rc::new(Url::from_file_path(dir.join("default.nix")).unwrap());
+        let found = root
+            .descendants()
+            .filter_map(Paren::cast)
+            .map(|paren| {
+                let apply = Apply::cast(paren.inner().unwrap()).unwrap();
+                let mut file = Rc::clone(&default);
+                let lambda = called_lambda(&mut files, &mut file, apply.lambda().unwrap()).unwrap();
+                (
+                    lambda.node().text().to_string(),
+                    file.to_file_path().unwrap(),
+                )
+            })
+            .collect_vec();
+        assert_eq!(
+            vec![
+                (
+                    String::from("{ stdenv, lib ? null }: stdenv.mkDerivation { }"),
+                    dir.join("package/default.nix")
+                ),
+                (String::from("x: x"), dir.join("id.nix")),
+            ],
+            found
+        );
+        // Imported files are kept around parsed
+        assert_eq!(2, files.len());
+    }
+
+    #[test]
+    fn test_merge_completions() {
+        let sorted = |items: Vec<CompletionItem>| {
+            items
+                .into_iter()
+                .sorted_by(|a, b| a.sort_text.cmp(&b.sort_text))
+                .map(|item| item.label)
+                .collect_vec()
+        };
+
+        let merged = merge_completions(
+            &[],
+            labelled(&["map", "attrs"]),
+            labelled(&["abort", "map", "toString"]),
+        );
+        assert_eq!(vec!["attrs", "map", "abort", "toString"], sorted(merged));
+
+        // manix names things by their full path
+        let merged = merge_completions(
+            &[String::from("lib")],
+            labelled(&["map"]),
+            labelled(&["lib.map", "map", "lib.mapAttrs"]),
+        );
+        assert_eq!(vec!["map", "lib.mapAttrs", "map"], sorted(merged));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{diagnostics, formatting::Formatter, App};
+use log::warn;
+use lsp_types::{DiagnosticSeverity, DidChangeConfigurationParams};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Settings the client sends as `initializationOptions`, or later through
+/// `workspace/didChangeConfiguration`. For example:
+///
+/// ```json
+/// {
+///   "formatter": { "command": ["nixfmt"], "timeout": 5000 },
+///   "lints": { "useless-parens": "off", "empty-let": "error" },
+///   "nixpkgs": "/home/user/nixpkgs"
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    pub formatter: Formatter,
+    /// Severities for lint rules, by name or code. `None` turns a rule off.
+    pub lints: HashMap<String, Option<DiagnosticSeverity>>,
+    /// Where to import nixpkgs from to look up packages, as a Nix
+    /// expression. `<nixpkgs>` if not set.
+    pub nixpkgs: Option<String>,
+}
+
+impl Config {
+    /// Reads the settings, leaving anything missing at its default
+    pub fn from_settings(settings: &Value) -> Self {
+        // Some clients namespace settings by the server they are meant for
+        let settings = settings.get("rnix").unwrap_or(settings);
+        Config {
+            formatter: Formatter::from_settings(&settings["formatter"]),
+            lints: lint_levels(&settings["lints"]),
+            nixpkgs: settings["nixpkgs"].as_str().map(String::from),
+        }
+    }
+    /// The expression to import nixpkgs from
+    pub fn nixpkgs(&self) -> String {
+        self.nixpkgs
+            .clone()
+            .unwrap_or_else(|| String::from("<nixpkgs>"))
+    }
+}
+
+fn lint_levels(settings: &Value) -> HashMap<String, Option<DiagnosticSeverity>> {
+    let mut levels = HashMap::new();
+    for (rule, level) in settings.as_object().into_iter().flatten() {
+        let severity = match level.as_str() {
+            Some("off") | Some("allow") => None,
+            Some("hint") => Some(DiagnosticSeverity::Hint),
+            Some("info") | Some("information") => Some(DiagnosticSeverity::Information),
+            Some("warn") | Some("warning") => Some(DiagnosticSeverity::Warning),
+            Some("error") | Some("deny") => Some(DiagnosticSeverity::Error),
+            _ => {
+                warn!("Unknown level {} for lint {}", level, rule);
+                continue;
+            }
+        };
+        levels.insert(rule.clone(), severity);
+    }
+    levels
+}
+
+impl App {
+    pub fn change_configuration(&mut self, params: DidChangeConfigurationParams) {
+        let config = Config::from_settings(&params.settings);
+        if config.nixpkgs() != self.config.nixpkgs() {
+            if let Ok(mut cache) = self.package_meta.lock() {
+                cache.clear();
+            }
+        }
+        self.config = config;
+
+        // Lint levels may have changed, so every open document needs checking
+        // again. Files only read to resolve imports aren't the client's concern.
+        let builtins = self.builtins();
+        let mut checked = Vec::new();
+        for uri in &self.open {
+            if let Some((ast, code)) = self.files.get(uri) {
+                let diagnostics = diagnostics::diagnostics(uri, code, ast, &builtins, &self.config);
+                checked.push((uri.clone(), diagnostics));
+            }
+        }
+        for (uri, diagnostics) in checked {
+            self.publish_diagnostics(uri, diagnostics);
+        }
+    }
+}
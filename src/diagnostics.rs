@@ -0,0 +1,820 @@
+use crate::{
+    builtin_args,
+    config::Config,
+    lints,
+    lookup::{self, LSPDetails, GLOBAL_BUILTINS},
+    utils,
+};
+use lsp_types::{
+    Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, DiagnosticTag, Location, Url,
+};
+use rnix::{
+    parser::{ParseError, AST},
+    types::*,
+    value::Value as ParsedValue,
+    NodeOrToken, SyntaxKind, SyntaxNode, TextRange, TextSize,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    rc::Rc,
+};
+
+/// Everything a check gets to look at
+pub struct CheckContext<'a> {
+    pub file: &'a Rc<Url>,
+    pub code: &'a str,
+    pub ast: &'a AST,
+    pub builtins: &'a HashMap<String, LSPDetails>,
+    pub config: &'a Config,
+}
+
+impl<'a> CheckContext<'a> {
+    pub fn diagnostic(
+        &self,
+        node: &SyntaxNode,
+        severity: DiagnosticSeverity,
+        message: String,
+    ) -> Diagnostic {
+        Diagnostic {
+            range: utils::range(self.code, node.text_range()),
+            severity: Some(severity),
+            message,
+            ..Diagnostic::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl CheckContext<'static> {
+    /// A context for checking `code` in tests, at `file:///default.nix`
+    /// unless another URI is given. What it borrows is leaked, which is fine
+    /// for the length of a test.
+    pub fn for_test(
+        code: &str,
+        uri: Option<Url>,
+        builtins: Option<HashMap<String, LSPDetails>>,
+    ) -> Self {
+        let uri = uri.unwrap_or_else(|| Url::parse("file:///default.nix").unwrap());
+        CheckContext {
+            file: Box::leak(Box::new(Rc::new(uri))),
+            code: Box::leak(code.to_owned().into_boxed_str()),
+            ast: Box::leak(Box::new(rnix::parse(code))),
+            builtins: Box::leak(Box::new(builtins.unwrap_or_default())),
+            config: Box::leak(Box::new(Config::default())),
+        }
+    }
+}
+
+/// A source of diagnostics, run whenever a file changes
+type Check = fn(&CheckContext, &mut Vec<Diagnostic>) -> Option<()>;
+
+const CHECKS: &[Check] = &[
+    parse_errors,
+    variables,
+    deprecated_builtins,
+    legacy_syntax,
+    missing_paths,
+    builtin_args::builtin_arguments,
+    lints::lints,
+];
+
+pub fn diagnostics(
+    uri: &Url,
+    code: &str,
+    ast: &AST,
+    builtins: &HashMap<String, LSPDetails>,
+    config: &Config,
+) -> Vec<Diagnostic> {
+    let file = Rc::new(uri.clone());
+    let context = CheckContext {
+        file: &file,
+        code,
+        ast,
+        builtins,
+        config,
+    };
+    let mut diagnostics = Vec::new();
+    for check in CHECKS {
+        check(&context, &mut diagnostics);
+    }
+    diagnostics
+}
+
+fn parse_errors(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    let root = context.ast.node();
+    for err in context.ast.errors() {
+        let range = utils::error_range(&root, &err);
+        let related_information =
+            parse_error_origin(&root, &err, range).map(|(origin, message)| {
+                vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: (**context.file).clone(),
+                        range: utils::range(context.code, origin),
+                    },
+                    message,
+                }]
+            });
+        diagnostics.push(Diagnostic {
+            range: utils::range(context.code, range),
+            severity: Some(DiagnosticSeverity::Error),
+            message: err.to_string(),
+            related_information,
+            ..Diagnostic::default()
+        });
+    }
+    Some(())
+}
+
+/// The place elsewhere in the file that a parse error is caused by, such as
+/// the first of two bindings or the bracket that was never closed
+fn parse_error_origin(
+    root: &SyntaxNode,
+    err: &ParseError,
+    range: TextRange,
+) -> Option<(TextRange, String)> {
+    match err {
+        ParseError::UnexpectedDoubleBind(_) => {
+            let error = root.descendants().find(|node| {
+                node.kind() == SyntaxKind::NODE_ERROR && range.contains_range(node.text_range())
+            })?;
+            let first = error.ancestors().find_map(Pattern::cast)?.at()?;
+            Some((
+                first.node().text_range(),
+                format!(
+                    "the arguments are already bound to `{}` here",
+                    first.as_str()
+                ),
+            ))
+        }
+        ParseError::DuplicatedArgs(_, name) => {
+            let pattern = root
+                .descendants()
+                .filter_map(Pattern::cast)
+                .filter(|pattern| pattern.node().text_range().contains_range(range))
+                .last()?;
+            let first = pattern
+                .entries()
+                .filter_map(|entry| entry.name())
+                .find(|ident| ident.as_str() == name)?;
+            if first.node().text_range() == range {
+                return None;
+            }
+            Some((
+                first.node().text_range(),
+                format!("`{}` is first declared here", name),
+            ))
+        }
+        ParseError::UnexpectedEOF => unclosed_delimiter(root, range.start(), None),
+        ParseError::UnexpectedEOFWanted(wanted) | ParseError::UnexpectedWanted(_, _, wanted) => {
+            unclosed_delimiter(root, range.start(), Some(&wanted[..]))
+        }
+        _ => None,
+    }
+}
+
+/// Finds the innermost bracket left open before `offset`, or if the parser
+/// wanted specific tokens, the innermost one that one of them would close
+fn unclosed_delimiter(
+    root: &SyntaxNode,
+    offset: TextSize,
+    wanted: Option<&[SyntaxKind]>,
+) -> Option<(TextRange, String)> {
+    let closing = |kind| match kind {
+        SyntaxKind::TOKEN_CURLY_B_OPEN => Some(SyntaxKind::TOKEN_CURLY_B_CLOSE),
+        SyntaxKind::TOKEN_SQUARE_B_OPEN => Some(SyntaxKind::TOKEN_SQUARE_B_CLOSE),
+        SyntaxKind::TOKEN_PAREN_OPEN => Some(SyntaxKind::TOKEN_PAREN_CLOSE),
+        SyntaxKind::TOKEN_INTERPOL_START => Some(SyntaxKind::TOKEN_INTERPOL_END),
+        SyntaxKind::TOKEN_DYNAMIC_START => Some(SyntaxKind::TOKEN_DYNAMIC_END),
+        _ => None,
+    };
+    let mut open = Vec::new();
+    let tokens = root
+        .descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .take_while(|token| token.text_range().end() <= offset);
+    for token in tokens {
+        if let Some(close) = closing(token.kind()) {
+            open.push((token, close));
+        } else if open
+            .last()
+            .map_or(false, |(_, close)| *close == token.kind())
+        {
+            open.pop();
+        }
+    }
+    let (token, _) = open
+        .into_iter()
+        .rev()
+        .find(|(_, close)| wanted.map_or(true, |wanted| wanted.contains(close)))?;
+    Some((
+        token.text_range(),
+        format!("unclosed `{}` opened here", token.text()),
+    ))
+}
+
+/// What resolving every variable of a file found
+struct Resolution {
+    /// The names of the bindings something refers to
+    used: HashSet<SyntaxNode>,
+    /// Variables that no binding provides
+    unbound: Vec<Ident>,
+}
+
+/// Resolves every variable below `root` in a single walk over its scopes
+fn resolve(file: &Rc<Url>, root: &SyntaxNode) -> Resolution {
+    let mut resolution = Resolution {
+        used: HashSet::new(),
+        unbound: Vec::new(),
+    };
+    utils::walk_scopes(file, root, &HashMap::new(), &mut |ident, scope| {
+        if !utils::is_variable_use(ident) {
+            return;
+        }
+        match scope.get(ident.as_str()) {
+            Some(var) => {
+                resolution.used.insert(var.key.clone());
+            }
+            None => resolution.unbound.push(ident.clone()),
+        }
+    });
+    resolution
+}
+
+/// Reports undefined variables and unused bindings, which both come from
+/// resolving the variables of the file
+fn variables(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    let root = context.ast.node();
+    let resolution = resolve(context.file, &root);
+    undefined_variables(context, &resolution.unbound, diagnostics);
+    unused(
+        context,
+        unused_in(context.file, &root, &resolution.used),
+        diagnostics,
+    );
+    Some(())
+}
+
+/// Flags variables that neither a binding, a builtin nor a `with` can
+/// provide. Within a `with` there is no telling what it brings into scope,
+/// so those names are only reported as possibly undefined.
+fn undefined_variables(
+    context: &CheckContext,
+    unbound: &[Ident],
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for ident in unbound {
+        let name = ident.as_str();
+        // Every builtin is also available globally with a `__` prefix
+        if GLOBAL_BUILTINS.contains(&name) || name.starts_with("__") || in_error(ident.node()) {
+            continue;
+        }
+        diagnostics.push(if utils::in_with_scope(ident.node()) {
+            context.diagnostic(
+                ident.node(),
+                DiagnosticSeverity::Information,
+                format!("possibly undefined variable `{}`", name),
+            )
+        } else {
+            context.diagnostic(
+                ident.node(),
+                DiagnosticSeverity::Error,
+                format!("undefined variable `{}`", name),
+            )
+        });
+    }
+}
+
+/// A binding that nothing refers to
+pub struct Unused {
+    pub ident: Ident,
+    /// What to delete to get rid of it: a `let` entry, a pattern entry, or
+    /// the name in an `inherit`
+    pub binding: SyntaxNode,
+}
+
+impl Unused {
+    /// Whether this is an argument, a variable or an attribute
+    pub fn describe(&self) -> &'static str {
+        let holder = self
+            .ident
+            .node()
+            .ancestors()
+            .find(|node| match node.kind() {
+                SyntaxKind::NODE_ATTR_SET
+                | SyntaxKind::NODE_LEGACY_LET
+                | SyntaxKind::NODE_LET_IN
+                | SyntaxKind::NODE_PAT_ENTRY => true,
+                _ => false,
+            });
+        match holder.map(|node| node.kind()) {
+            Some(SyntaxKind::NODE_PAT_ENTRY) => "argument",
+            Some(SyntaxKind::NODE_ATTR_SET) => "attribute",
+            _ => "variable",
+        }
+    }
+}
+
+/// Finds `let` bindings and pattern entries that are never used. Patterns
+/// with `...` or `@` are left alone, since they have to name the arguments
+/// they take, and so are names starting with `_`. Attributes of a `rec` set
+/// are part of its value, so they only count as unused when a single
+/// attribute is selected from the set right away.
+pub fn unused_bindings(file: &Rc<Url>, root: &SyntaxNode) -> Vec<Unused> {
+    unused_in(file, root, &resolve(file, root).used)
+}
+
+/// The bindings below `root` that aren't among the `used` ones
+fn unused_in(file: &Rc<Url>, root: &SyntaxNode, used: &HashSet<SyntaxNode>) -> Vec<Unused> {
+    let mut declared = HashSet::new();
+    let mut candidates = Vec::new();
+    for node in root.descendants() {
+        // Of several bindings with the same name, only the first one counts
+        let mut bindings = HashMap::new();
+        utils::add_bindings(file, &mut bindings, &node);
+        declared.extend(bindings.into_iter().map(|(_, var)| var.key));
+
+        match ParsedType::try_from(node) {
+            Ok(ParsedType::LetIn(let_in)) => let_bindings(&let_in, &mut candidates),
+            Ok(ParsedType::LegacyLet(let_)) => let_bindings(&let_, &mut candidates),
+            Ok(ParsedType::AttrSet(set)) => {
+                if let Some(selected) = selected_attr(&set) {
+                    let mut attrs = Vec::new();
+                    let_bindings(&set, &mut attrs);
+                    candidates.extend(
+                        attrs
+                            .into_iter()
+                            .filter(|attr| attr.ident.as_str() != selected),
+                    );
+                }
+            }
+            Ok(ParsedType::Pattern(pattern)) => {
+                if !pattern.ellipsis() && pattern.at().is_none() {
+                    for entry in pattern.entries() {
+                        if let Some(ident) = entry.name() {
+                            candidates.push(Unused {
+                                ident,
+                                binding: entry.node().clone(),
+                            });
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter(|candidate| {
+            let name = candidate.ident.as_str();
+            if name.starts_with('_') || in_error(&candidate.binding) {
+                return false;
+            }
+            let key = candidate.ident.node();
+            declared.contains(key) && !used.contains(key)
+        })
+        .collect()
+}
+
+/// The attribute taken from a `rec` set straight away, like `b` in
+/// `(rec { a = 1; b = a; }).b`
+fn selected_attr(set: &AttrSet) -> Option<String> {
+    if !set.recursive() {
+        return None;
+    }
+    let mut node = set.node().clone();
+    while node.parent()?.kind() == SyntaxKind::NODE_PAREN {
+        node = node.parent()?;
+    }
+    let select = Select::cast(node.parent()?)?;
+    if select.set()? != node {
+        return None;
+    }
+    Some(Ident::cast(select.index()?)?.as_str().to_owned())
+}
+
+fn let_bindings<T: EntryHolder>(holder: &T, candidates: &mut Vec<Unused>) {
+    for entry in holder.entries() {
+        let mut path = match entry.key() {
+            Some(key) => key.path(),
+            None => continue,
+        };
+        let ident = path.next().and_then(Ident::cast);
+        if let (Some(ident), None) = (ident, path.next()) {
+            // `let { body = ...; }` evaluates to `body`
+            if holder.node().kind() != SyntaxKind::NODE_LEGACY_LET || ident.as_str() != "body" {
+                candidates.push(Unused {
+                    ident,
+                    binding: entry.node().clone(),
+                });
+            }
+        }
+    }
+    for inherit in holder.inherits() {
+        for ident in inherit.idents() {
+            let binding = ident.node().clone();
+            candidates.push(Unused { ident, binding });
+        }
+    }
+}
+
+fn unused(context: &CheckContext, unused: Vec<Unused>, diagnostics: &mut Vec<Diagnostic>) {
+    for unused in unused {
+        let what = unused.describe();
+        let mut diagnostic = context.diagnostic(
+            unused.ident.node(),
+            DiagnosticSeverity::Warning,
+            format!("unused {} `{}`", what, unused.ident.as_str()),
+        );
+        diagnostic.tags = Some(vec![DiagnosticTag::Unnecessary]);
+        diagnostics.push(diagnostic);
+    }
+}
+
+/// Flags uses of builtins whose documentation marks them deprecated
+fn deprecated_builtins(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    for node in context.ast.node().descendants() {
+        if let Some(ident) = Ident::cast(node.clone()) {
+            if !utils::is_variable_use(&ident) {
+                continue;
+            }
+        }
+        let name = match lookup::builtin_name(context.file, &node) {
+            Some(name) => name,
+            None => continue,
+        };
+        let details = match context.builtins.get(&name) {
+            Some(details) if details.deprecated => details,
+            _ => continue,
+        };
+        let message = match details.deprecation() {
+            Some(replacement) => format!("`{}` is deprecated: {}", name, replacement),
+            None => format!("`{}` is deprecated", name),
+        };
+        let mut diagnostic = context.diagnostic(&node, DiagnosticSeverity::Warning, message);
+        diagnostic.tags = Some(vec![DiagnosticTag::Deprecated]);
+        diagnostics.push(diagnostic);
+    }
+    Some(())
+}
+
+/// An outdated construct, with the modern replacement if there is one
+pub struct Legacy {
+    pub range: TextRange,
+    pub message: &'static str,
+    /// The title of the rewrite and the text to replace `range` with
+    pub rewrite: Option<(&'static str, String)>,
+}
+
+/// Finds `let { ...; body = ...; }`, URL literals, and `or` used as a name
+pub fn legacy_constructs(root: &SyntaxNode) -> Vec<Legacy> {
+    let mut found = Vec::new();
+    for element in root.descendants_with_tokens() {
+        match element {
+            NodeOrToken::Node(node) => {
+                if let Some(let_) = LegacyLet::cast(node) {
+                    found.push(Legacy {
+                        range: let_.node().text_range(),
+                        message: "`let { ... }` is deprecated, use `let ... in` instead",
+                        rewrite: rewrite_legacy_let(&let_)
+                            .map(|text| ("Rewrite to `let ... in`", text)),
+                    });
+                }
+            }
+            NodeOrToken::Token(token) => match token.kind() {
+                SyntaxKind::TOKEN_URI => found.push(Legacy {
+                    range: token.text_range(),
+                    message: "URL literals are deprecated, use a string instead",
+                    rewrite: Some(("Quote URL", format!("\"{}\"", token.text()))),
+                }),
+                SyntaxKind::TOKEN_OR_DEFAULT
+                    if token.parent().kind() != SyntaxKind::NODE_OR_DEFAULT =>
+                {
+                    // As an attribute name, a quoted `"or"` means the same
+                    let previous =
+                        std::iter::successors(token.prev_token(), |token| token.prev_token())
+                            .find(|token| token.kind() != SyntaxKind::TOKEN_WHITESPACE);
+                    let in_key = token
+                        .parent()
+                        .ancestors()
+                        .take(2)
+                        .any(|node| node.kind() == SyntaxKind::NODE_KEY);
+                    let after_dot =
+                        previous.map_or(false, |token| token.kind() == SyntaxKind::TOKEN_DOT);
+                    found.push(Legacy {
+                        range: token.text_range(),
+                        message: "using `or` as a name is deprecated",
+                        rewrite: if in_key || after_dot {
+                            Some(("Quote `or`", String::from("\"or\"")))
+                        } else {
+                            None
+                        },
+                    });
+                }
+                _ => (),
+            },
+        }
+    }
+    found
+}
+
+/// Turns `let { a = 1; body = a; }` into `let a = 1; in a`, keeping the
+/// bindings as they are written.
+fn rewrite_legacy_let(let_: &LegacyLet) -> Option<String> {
+    let node = let_.node();
+    let tokens = || {
+        node.children_with_tokens()
+            .filter_map(NodeOrToken::into_token)
+    };
+    let open = tokens().find(|token| token.kind() == SyntaxKind::TOKEN_CURLY_B_OPEN)?;
+    let close = tokens().find(|token| token.kind() == SyntaxKind::TOKEN_CURLY_B_CLOSE)?;
+    let body = let_.entries().find(|entry| {
+        entry.key().map_or(false, |key| {
+            let path = key.path().collect::<Vec<_>>();
+            path.len() == 1 && path[0].text() == "body"
+        })
+    })?;
+    let value = body.value()?;
+
+    // Other bindings may refer to `body`, in which case it has to stay
+    let body_used = node
+        .descendants()
+        .filter_map(Ident::cast)
+        .any(|ident| ident.as_str() == "body" && utils::is_variable_use(&ident));
+    // Offsets into the text of the let, between its braces
+    let offset = |at| usize::from(at) - usize::from(open.text_range().end());
+    let text = node.text().to_string();
+    let text = &text[usize::from(open.text_range().end() - node.text_range().start())..];
+    let mut bindings = text[..offset(close.text_range().start())].to_owned();
+    if !body_used {
+        let mut removed = body.node().text_range();
+        if let Some(NodeOrToken::Token(whitespace)) = body.node().prev_sibling_or_token() {
+            if whitespace.kind() == SyntaxKind::TOKEN_WHITESPACE {
+                removed = TextRange::new(whitespace.text_range().start(), removed.end());
+            }
+        }
+        bindings.replace_range(offset(removed.start())..offset(removed.end()), "");
+    }
+    if !bindings.starts_with(char::is_whitespace) {
+        bindings.insert(0, ' ');
+    }
+    if !bindings.ends_with(char::is_whitespace) {
+        bindings.push(' ');
+    }
+    let result = if body_used {
+        String::from("body")
+    } else {
+        value.text().to_string()
+    };
+    Some(format!("let{}in {}", bindings, result))
+}
+
+fn legacy_syntax(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    for legacy in legacy_constructs(&context.ast.node()) {
+        diagnostics.push(Diagnostic {
+            range: utils::range(context.code, legacy.range),
+            severity: Some(DiagnosticSeverity::Warning),
+            message: legacy.message.into(),
+            tags: Some(vec![DiagnosticTag::Deprecated]),
+            ..Diagnostic::default()
+        });
+    }
+    Some(())
+}
+
+/// Flags path literals that point nowhere. Imports also need a file, or
+/// a directory with a default.nix in it.
+fn missing_paths(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    for value in context.ast.node().descendants().filter_map(Value::cast) {
+        let (anchor, path) = match value.to_value() {
+            Ok(ParsedValue::Path(anchor, path)) => (anchor, path),
+            _ => continue,
+        };
+        let resolved = match utils::resolve_path(context.file, anchor, &path) {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+        let imported = lookup::is_import_target(context.file, value.node());
+        let text = value.node().text();
+        let message = if resolved.is_file() {
+            continue;
+        } else if resolved.is_dir() {
+            if !imported || resolved.join("default.nix").is_file() {
+                continue;
+            }
+            format!(
+                "`{}` is a directory, but has no default.nix to import",
+                text
+            )
+        } else if imported {
+            format!("imported file `{}` does not exist", text)
+        } else {
+            format!("`{}` does not exist", text)
+        };
+        diagnostics.push(context.diagnostic(value.node(), DiagnosticSeverity::Warning, message));
+    }
+    Some(())
+}
+
+/// Whether the parser gave up somewhere around this node, in which case
+/// the structure around it can't be trusted.
+pub fn in_error(node: &SyntaxNode) -> bool {
+    node.ancestors()
+        .any(|node| node.kind() == SyntaxKind::NODE_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(code: &str, check: Check) -> Vec<(String, DiagnosticSeverity)> {
+        let mut builtins = HashMap::new();
+        builtins.insert(
+            String::from("toPath"),
+            LSPDetails {
+                datatype: utils::Datatype::Lambda,
+                var: None,
+                documentation: Some(String::from(
+                    "**DEPRECATED.** Use `/. + \"/path\"` to convert a string into an absolute\n    path.\n\n    More text.",
+                )),
+                deprecated: true,
+                params: None,
+                args: None,
+            },
+        );
+        let context = CheckContext::for_test(code, None, Some(builtins));
+        let mut diagnostics = Vec::new();
+        check(&context, &mut diagnostics);
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| (diagnostic.message, diagnostic.severity.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let related = |code: &str| {
+            let context = CheckContext::for_test(code, None, None);
+            let mut diagnostics = Vec::new();
+            parse_errors(&context, &mut diagnostics);
+            assert_eq!(context.ast.errors().len(), diagnostics.len());
+            diagnostics
+                .into_iter()
+                .flat_map(|diagnostic| diagnostic.related_information.unwrap_or_default())
+                .map(|related| {
+                    let start = utils::lookup_pos(code, related.location.range.start).unwrap();
+                    let end = utils::lookup_pos(code, related.location.range.end).unwrap();
+                    (code[start..end].to_owned(), related.message)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![(
+                String::from("a"),
+                String::from("the arguments are already bound to `a` here")
+            )],
+            related("a@{ b }@c: b")
+        );
+        let unclosed = related("{\n  a = [ (b) 1;\n  c = 2;\n");
+        assert!(unclosed.contains(&(String::from("["), String::from("unclosed `[` opened here"))));
+        assert!(unclosed.contains(&(String::from("{"), String::from("unclosed `{` opened here"))));
+    }
+
+    #[test]
+    fn test_undefined_variables() {
+        let code = "{ a, ... }: let b = a; inherit c; in rec { d = b; e = d + f; g = with a; h; inherit (b) i; }";
+        assert_eq!(
+            vec![
+                (
+                    String::from("undefined variable `c`"),
+                    DiagnosticSeverity::Error
+                ),
+                (
+                    String::from("undefined variable `f`"),
+                    DiagnosticSeverity::Error
+                ),
+                (
+                    String::from("possibly undefined variable `h`"),
+                    DiagnosticSeverity::Information
+                ),
+                (
+                    String::from("unused variable `c`"),
+                    DiagnosticSeverity::Warning
+                ),
+            ],
+            check(code, variables)
+        );
+    }
+
+    #[test]
+    fn test_unused() {
+        let code = "{ a, b, _c }: let d = a; e = 1; _f = 2; inherit (d) g; in { h }: { x, ... }: { i, y }@j: d";
+        assert_eq!(
+            vec![
+                "unused argument `b`",
+                "unused variable `e`",
+                "unused variable `g`",
+                "unused argument `h`"
+            ],
+            check(code, variables)
+                .into_iter()
+                .map(|(message, _)| message)
+                .collect::<Vec<_>>()
+        );
+
+        let code = "[ (rec { a = 1; b = a; c = 2; }).b rec { d = 1; } ]";
+        assert_eq!(
+            vec![(
+                String::from("unused attribute `c`"),
+                DiagnosticSeverity::Warning
+            )],
+            check(code, variables)
+        );
+    }
+
+    #[test]
+    fn test_deprecated_builtins() {
+        let code = "{ toPath }: [ (builtins.toPath x) (toPath x) (builtins.toString x) ]";
+        assert_eq!(
+            vec![(
+                String::from(
+                    "`toPath` is deprecated: Use `/. + \"/path\"` to convert a string into an absolute path."
+                ),
+                DiagnosticSeverity::Warning
+            )],
+            check(code, deprecated_builtins)
+        );
+    }
+
+    #[test]
+    fn test_legacy_constructs() {
+        let code = "[ (let { a = 1;\n  body = a; }) (let {b=body; body=2;}) http://example.org ]";
+        let found = legacy_constructs(&rnix::parse(code).node())
+            .into_iter()
+            .map(|legacy| legacy.rewrite.map(|(_, text)| text))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Some(String::from("let a = 1; in a")),
+                Some(String::from("let b=body; body=2; in body")),
+                Some(String::from("\"http://example.org\"")),
+            ],
+            found
+        );
+
+        let code = "{ or = 1; a = x.or; }";
+        let found = legacy_constructs(&rnix::parse(code).node())
+            .into_iter()
+            .map(|legacy| (legacy.message, legacy.rewrite.map(|(_, text)| text)))
+            .collect::<Vec<_>>();
+        let quoted = (
+            "using `or` as a name is deprecated",
+            Some(String::from("\"or\"")),
+        );
+        assert_eq!(vec![quoted.clone(), quoted], found);
+    }
+
+    #[test]
+    fn test_missing_paths() {
+        let temp = utils::TempDir::new("missing-paths");
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::create_dir_all(dir.join("module")).unwrap();
+        std::fs::write(dir.join("module/default.nix"), "{}").unwrap();
+        std::fs::write(dir.join("file.nix"), "{}").unwrap();
+
+        let code = "{ imports = [ ./module ./empty ./gone.nix ]; a = import ./file.nix; src = ./empty; b = ./nope; }";
+        let uri = Url::from_file_path(dir.join("default.nix")).unwrap();
+        let context = CheckContext::for_test(code, Some(uri), None);
+        let mut diagnostics = Vec::new();
+        missing_paths(&context, &mut diagnostics);
+        assert_eq!(
+            vec![
+                (
+                    String::from("`./empty` is a directory, but has no default.nix to import"),
+                    DiagnosticSeverity::Warning
+                ),
+                (
+                    String::from("imported file `./gone.nix` does not exist"),
+                    DiagnosticSeverity::Warning
+                ),
+                (
+                    String::from("`./nope` does not exist"),
+                    DiagnosticSeverity::Warning
+                ),
+            ],
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic.message, diagnostic.severity.unwrap()))
+                .collect::<Vec<_>>()
+        );
+
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        let context = CheckContext::for_test(code, Some(untitled), None);
+        let mut diagnostics = Vec::new();
+        missing_paths(&context, &mut diagnostics);
+        assert!(diagnostics.is_empty());
+    }
+}
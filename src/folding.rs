@@ -0,0 +1,121 @@
+use crate::{utils, App};
+use lsp_types::{FoldingRange, FoldingRangeKind, FoldingRangeParams};
+use rnix::{NodeOrToken, SyntaxKind, SyntaxNode, SyntaxToken, TextSize};
+
+impl App {
+    pub fn folding_ranges(&self, params: &FoldingRangeParams) -> Option<Vec<FoldingRange>> {
+        let (ast, code) = self.files.get(&params.text_document.uri)?;
+        Some(folding_ranges(code, &ast.node()))
+    }
+}
+
+/// Finds every multi-line set, `let`, list, lambda, string and comment block.
+/// A range ends on the line before its closing delimiter so that the
+/// delimiter stays visible when the range is folded.
+pub fn folding_ranges(code: &str, root: &SyntaxNode) -> Vec<FoldingRange> {
+    let line_of = |offset: TextSize| utils::offset_to_pos(code, usize::from(offset)).line;
+    let mut ranges = Vec::new();
+
+    for node in root.descendants() {
+        let closing_line = |token: Option<SyntaxToken>| {
+            token.map_or(line_of(node.text_range().end()), |token| {
+                line_of(token.text_range().start()).saturating_sub(1)
+            })
+        };
+        let end_line = match node.kind() {
+            SyntaxKind::NODE_ATTR_SET
+            | SyntaxKind::NODE_LEGACY_LET
+            | SyntaxKind::NODE_LIST
+            | SyntaxKind::NODE_PATTERN
+            | SyntaxKind::NODE_STRING => closing_line(node.last_token().filter(|token| {
+                match token.kind() {
+                    SyntaxKind::TOKEN_CURLY_B_CLOSE
+                    | SyntaxKind::TOKEN_SQUARE_B_CLOSE
+                    | SyntaxKind::TOKEN_STRING_END => true,
+                    _ => false,
+                }
+            })),
+            SyntaxKind::NODE_LET_IN => closing_line(
+                node.children_with_tokens()
+                    .filter_map(NodeOrToken::into_token)
+                    .find(|token| token.kind() == SyntaxKind::TOKEN_IN),
+            ),
+            SyntaxKind::NODE_LAMBDA => line_of(node.text_range().end()),
+            _ => continue,
+        };
+        let start_line = line_of(node.text_range().start());
+        if end_line > start_line {
+            ranges.push(fold(start_line, end_line, None));
+        }
+    }
+
+    // Runs of `#` comments on consecutive lines fold together,
+    // while each `/* */` comment folds on its own.
+    let mut run: Option<(u64, u64)> = None;
+    let comments = root
+        .descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .filter(|token| token.kind() == SyntaxKind::TOKEN_COMMENT);
+    for comment in comments {
+        let start_line = line_of(comment.text_range().start());
+        let end_line = line_of(comment.text_range().end());
+        if !comment.text().starts_with('#') {
+            if end_line > start_line {
+                ranges.push(fold(start_line, end_line, Some(FoldingRangeKind::Comment)));
+            }
+            continue;
+        }
+        run = match run {
+            Some((first, last)) if start_line == last + 1 => Some((first, start_line)),
+            Some((first, last)) => {
+                if last > first {
+                    ranges.push(fold(first, last, Some(FoldingRangeKind::Comment)));
+                }
+                Some((start_line, start_line))
+            }
+            None => Some((start_line, start_line)),
+        };
+    }
+    if let Some((first, last)) = run {
+        if last > first {
+            ranges.push(fold(first, last, Some(FoldingRangeKind::Comment)));
+        }
+    }
+
+    ranges.sort_by_key(|range| range.start_line);
+    ranges
+}
+
+fn fold(start_line: u64, end_line: u64, kind: Option<FoldingRangeKind>) -> FoldingRange {
+    FoldingRange {
+        start_line,
+        start_character: None,
+        end_line,
+        end_character: None,
+        kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folding_ranges() {
+        let code = "# a\n# b\nlet\n  x = [\n    1\n  ];\n  s = ''\n    echo\n  '';\nin x\n";
+        let root = rnix::parse(code).node();
+        let ranges = folding_ranges(code, &root)
+            .into_iter()
+            .map(|range| (range.start_line, range.end_line, range.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (0, 1, Some(FoldingRangeKind::Comment)),
+                (2, 8, None),
+                (3, 4, None),
+                (6, 7, None),
+            ],
+            ranges
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use crate::{utils, App};
+use lsp_types::{
+    DocumentFormattingParams, DocumentOnTypeFormattingParams, DocumentRangeFormattingParams,
+    Position, Range, TextEdit,
+};
+use rnix::{parser::AST, NodeOrToken, SyntaxKind, SyntaxNode, TextRange, TextSize};
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    io::{Read, Write},
+    iter::Peekable,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long an external formatter may take before it gets killed
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The program that formats a file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Formatter {
+    /// The nixpkgs-fmt library, built into the server
+    NixpkgsFmt,
+    /// Any program that reads a file on stdin and writes it back formatted
+    /// on stdout, like nixfmt or alejandra
+    External { command: Vec<String>, timeout: Duration },
+}
+
+impl Default for Formatter {
+    fn default() -> Self {
+        Formatter::NixpkgsFmt
+    }
+}
+
+impl Formatter {
+    /// Reads the `formatter` setting, which is either the name of a command,
+    /// or `{ "command": ["nixfmt", "--width=80"], "timeout": 5000 }` with the
+    /// timeout in milliseconds.
+    pub fn from_settings(settings: &Value) -> Self {
+        let (command, timeout) = match settings {
+            Value::String(command) => (
+                command.split_whitespace().map(String::from).collect::<Vec<_>>(),
+                None,
+            ),
+            Value::Object(object) => {
+                let command = match object.get("command") {
+                    Some(Value::String(command)) => {
+                        command.split_whitespace().map(String::from).collect()
+                    }
+                    Some(Value::Array(parts)) => parts
+                        .iter()
+                        .filter_map(|part| part.as_str().map(String::from))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                (command, object.get("timeout").and_then(Value::as_u64))
+            }
+            _ => return Formatter::NixpkgsFmt,
+        };
+        match command.first().map(String::as_str) {
+            None | Some("nixpkgs-fmt") => Formatter::NixpkgsFmt,
+            Some(_) => Formatter::External {
+                command,
+                timeout: timeout.map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+            },
+        }
+    }
+    /// Formats a whole file, or explains why that didn't work
+    pub fn format(&self, ast: &AST, code: &str) -> Result<String, String> {
+        match self {
+            Formatter::NixpkgsFmt => Ok(nixpkgs_fmt::reformat_node(&ast.node()).text().to_string()),
+            Formatter::External { command, timeout } => run_external(command, *timeout, code),
+        }
+    }
+}
+
+/// Pipes `code` through an external formatter. The output is only trusted
+/// if the formatter exits successfully within `timeout`.
+fn run_external(command: &[String], timeout: Duration, code: &str) -> Result<String, String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| String::from("the formatter command is empty"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("could not run formatter `{}`: {}", program, err))?;
+
+    // Feed and drain the pipes on their own threads, so that a formatter
+    // blocking on a full pipe can't hang us.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = code.to_owned();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let error_reader = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        output
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "formatter `{}` timed out after {}ms",
+                    program,
+                    timeout.as_millis()
+                ));
+            }
+            Err(err) => return Err(format!("formatter `{}` failed: {}", program, err)),
+        }
+    };
+
+    // A formatter that exits without reading all of stdin is fine,
+    // so write errors only matter through the exit status.
+    let _ = writer.join();
+    let errors = error_reader.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("formatter `{}` failed ({}): {}", program, status, errors.trim()));
+    }
+    match reader.join() {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(format!("could not read output of formatter `{}`: {}", program, err)),
+        Err(_) => Err(format!("could not read output of formatter `{}`", program)),
+    }
+}
+
+impl App {
+    pub fn formatting(&self, params: &DocumentFormattingParams) -> Result<Vec<TextEdit>, String> {
+        let (ast, code) = match self.files.get(&params.text_document.uri) {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+        let formatted = self.config.formatter.format(ast, code)?;
+        Ok(minimal_edits(code, &formatted))
+    }
+    /// Formats the smallest node that covers the selection, and leaves the
+    /// rest of the file alone. External formatters only understand whole
+    /// files, so with those the file is formatted and only the edits within
+    /// the selection are kept.
+    pub fn range_formatting(
+        &self,
+        params: &DocumentRangeFormattingParams,
+    ) -> Result<Vec<TextEdit>, String> {
+        let (ast, code) = match self.files.get(&params.text_document.uri) {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+        if let Formatter::External { .. } = self.config.formatter {
+            let formatted = self.config.formatter.format(ast, code)?;
+            let selection = params.range;
+            return Ok(minimal_edits(code, &formatted)
+                .into_iter()
+                .filter(|edit| edit.range.start <= selection.end && selection.start <= edit.range.end)
+                .collect());
+        }
+
+        Ok(node_covering(ast, code, params.range)
+            .map_or_else(Vec::new, |node| format_node(code, &node, None)))
+    }
+    pub fn on_type_formatting(
+        &self,
+        params: &DocumentOnTypeFormattingParams,
+    ) -> Option<Vec<TextEdit>> {
+        let (ast, code) = self
+            .files
+            .get(&params.text_document_position.text_document.uri)?;
+        let offset = utils::lookup_pos(code, params.text_document_position.position)?;
+        on_type_edits(code, &ast.node(), offset, &params.ch)
+    }
+}
+
+fn node_covering(ast: &AST, code: &str, range: Range) -> Option<SyntaxNode> {
+    let start = utils::lookup_pos(code, range.start)?;
+    let end = utils::lookup_pos(code, range.end)?;
+    let range = TextRange::new(TextSize::try_from(start).ok()?, TextSize::try_from(end).ok()?);
+    Some(match ast.node().covering_element(range) {
+        NodeOrToken::Node(node) => node,
+        NodeOrToken::Token(token) => token.parent(),
+    })
+}
+
+/// What to do after `ch` was typed, with the cursor now at `offset`:
+/// - after `;`, reformat the binding it ends
+/// - after `}`, reformat the set it closes
+/// - after a newline between the bindings of a set or `let`, indent the
+///   new line for the next binding
+fn on_type_edits(code: &str, root: &SyntaxNode, offset: usize, ch: &str) -> Option<Vec<TextEdit>> {
+    let offset = TextSize::try_from(offset).ok()?;
+    let token = root.token_at_offset(offset).left_biased()?;
+    let broken = |node: &SyntaxNode| {
+        node.descendants()
+            .any(|node| node.kind() == SyntaxKind::NODE_ERROR)
+    };
+
+    match ch {
+        ";" if token.kind() == SyntaxKind::TOKEN_SEMICOLON => {
+            let binding = token.parent();
+            let container = binding.parent()?;
+            let is_binding = match binding.kind() {
+                SyntaxKind::NODE_KEY_VALUE | SyntaxKind::NODE_INHERIT => true,
+                _ => false,
+            };
+            if !is_binding || broken(&binding) {
+                return None;
+            }
+            let indent = format!(
+                "{}  ",
+                line_indent(code, usize::from(container.text_range().start()))
+            );
+            Some(format_node(code, &binding, Some(&indent)))
+        }
+        "}" if token.kind() == SyntaxKind::TOKEN_CURLY_B_CLOSE => {
+            let set = token.parent();
+            if set.kind() != SyntaxKind::NODE_ATTR_SET || broken(&set) {
+                return None;
+            }
+            Some(format_node(code, &set, None))
+        }
+        "\n" => {
+            let container = token.parent();
+            let (open, close) = match container.kind() {
+                SyntaxKind::NODE_ATTR_SET => (
+                    SyntaxKind::TOKEN_CURLY_B_OPEN,
+                    SyntaxKind::TOKEN_CURLY_B_CLOSE,
+                ),
+                SyntaxKind::NODE_LET_IN => (SyntaxKind::TOKEN_LET, SyntaxKind::TOKEN_IN),
+                SyntaxKind::NODE_LEGACY_LET => (
+                    SyntaxKind::TOKEN_CURLY_B_OPEN,
+                    SyntaxKind::TOKEN_CURLY_B_CLOSE,
+                ),
+                _ => return None,
+            };
+            let find = |kind| {
+                container
+                    .children_with_tokens()
+                    .filter_map(NodeOrToken::into_token)
+                    .find(|token| token.kind() == kind)
+            };
+            if find(open)?.text_range().end() >= offset
+                || find(close).map_or(false, |close| close.text_range().start() < offset)
+            {
+                return None;
+            }
+
+            let offset = usize::from(offset);
+            let line_start = code[..offset].rfind('\n').map_or(0, |n| n + 1);
+            let rest = &code[line_start..];
+            let rest = rest.trim_start_matches(|c| c == ' ' || c == '\t');
+            let old_indent = &code[line_start..code.len() - rest.len()];
+
+            let outer = line_indent(code, usize::from(container.text_range().start()));
+            let closes = match close {
+                SyntaxKind::TOKEN_IN => {
+                    rest.starts_with("in") && !rest[2..].starts_with(|c: char| c.is_alphanumeric())
+                }
+                _ => rest.starts_with('}'),
+            };
+            let indent = if closes {
+                outer.to_owned()
+            } else {
+                format!("{}  ", outer)
+            };
+            if indent == old_indent {
+                return Some(Vec::new());
+            }
+            let start = utils::offset_to_pos(code, line_start);
+            let end = utils::offset_to_pos(code, line_start + old_indent.len());
+            Some(vec![TextEdit {
+                range: Range::new(start, end),
+                new_text: indent,
+            }])
+        }
+        _ => None,
+    }
+}
+
+/// Reformats `node` where it stands, shifted to the indentation of the line
+/// it starts on. With `indent` given, that line is reindented as well,
+/// provided nothing else precedes the node there.
+fn format_node(code: &str, node: &SyntaxNode, indent: Option<&str>) -> Vec<TextEdit> {
+    let start = usize::from(node.text_range().start());
+    let end = usize::from(node.text_range().end());
+    let line_start = code[..start].rfind('\n').map_or(0, |n| n + 1);
+
+    let mut new_code = String::from(&code[..start]);
+    let indent = match indent {
+        Some(indent) if code[line_start..start].trim().is_empty() => {
+            new_code.truncate(line_start);
+            new_code.push_str(indent);
+            indent
+        }
+        _ => line_indent(code, start),
+    };
+    new_code.push_str(&reindent(
+        &nixpkgs_fmt::reformat_node(&node.clone_subtree())
+            .text()
+            .to_string(),
+        indent,
+    ));
+    new_code.push_str(&code[end..]);
+    minimal_edits(code, &new_code)
+}
+
+/// Turns a reformatted file into edits that only touch the whitespace that
+/// actually changed, so that editors can keep cursors and marks in place.
+/// Should the formatter have changed anything besides whitespace, this falls
+/// back to replacing everything between the first and the last difference.
+pub fn minimal_edits(old: &str, new: &str) -> Vec<TextEdit> {
+    fn whitespace_run<I: Iterator<Item = (usize, char)>>(
+        text: &str,
+        chars: &mut Peekable<I>,
+        mut position: Option<&mut Position>,
+    ) -> (usize, usize) {
+        let start = chars.peek().map_or(text.len(), |&(i, _)| i);
+        while let Some(&(_, c)) = chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            if let Some(position) = position.as_mut() {
+                advance(position, c);
+            }
+            chars.next();
+        }
+        (start, chars.peek().map_or(text.len(), |&(i, _)| i))
+    }
+
+    let mut edits = Vec::new();
+    let mut old_chars = old.char_indices().peekable();
+    let mut new_chars = new.char_indices().peekable();
+    let mut position = Position::new(0, 0);
+    loop {
+        let run_start = position;
+        let (old_start, old_end) = whitespace_run(old, &mut old_chars, Some(&mut position));
+        let (new_start, new_end) = whitespace_run(new, &mut new_chars, None);
+        let (old_run, new_run) = (&old[old_start..old_end], &new[new_start..new_end]);
+        if old_run != new_run {
+            let (prefix, suffix) = common_affixes(old_run, new_run);
+            let mut start = run_start;
+            old_run[..prefix]
+                .chars()
+                .for_each(|c| advance(&mut start, c));
+            let mut end = start;
+            old_run[prefix..old_run.len() - suffix]
+                .chars()
+                .for_each(|c| advance(&mut end, c));
+            edits.push(TextEdit {
+                range: Range::new(start, end),
+                new_text: new_run[prefix..new_run.len() - suffix].to_owned(),
+            });
+        }
+        match (old_chars.next(), new_chars.next()) {
+            (None, None) => break,
+            (Some((_, a)), Some((_, b))) if a == b => advance(&mut position, a),
+            _ => return single_edit(old, new),
+        }
+    }
+    edits
+}
+
+fn advance(position: &mut Position, c: char) {
+    if c == '\n' {
+        position.line += 1;
+        position.character = 0;
+    } else {
+        position.character += c.len_utf16() as u64;
+    }
+}
+
+/// The lengths of the longest common prefix and suffix of both texts,
+/// which don't overlap.
+fn common_affixes(old: &str, new: &str) -> (usize, usize) {
+    let prefix = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum::<usize>();
+    let suffix = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum::<usize>();
+    (prefix, suffix)
+}
+
+/// Replaces whatever lies between the common prefix and suffix of both texts
+fn single_edit(old: &str, new: &str) -> Vec<TextEdit> {
+    let (prefix, suffix) = common_affixes(old, new);
+    if prefix == old.len() && prefix == new.len() {
+        return Vec::new();
+    }
+    vec![TextEdit {
+        range: Range::new(
+            utils::offset_to_pos(old, prefix),
+            utils::offset_to_pos(old, old.len() - suffix),
+        ),
+        new_text: new[prefix..new.len() - suffix].to_owned(),
+    }]
+}
+
+/// The whitespace that the line containing `offset` starts with
+fn line_indent(code: &str, offset: usize) -> &str {
+    let line_start = code[..offset].rfind('\n').map_or(0, |n| n + 1);
+    let line = &code[line_start..];
+    &line[..line.len() - line.trim_start_matches(|c| c == ' ' || c == '\t').len()]
+}
+
+/// A node formatted on its own is indented as if it started at column zero,
+/// so every line but the first has to be shifted back to where the node is.
+fn reindent(text: &str, indent: &str) -> String {
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 || line.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_edits() {
+        let edits = minimal_edits("{a=1;\n  b = 2;}", "{ a = 1;\n  b = 2; }");
+        assert_eq!(
+            vec![
+                (Position::new(0, 1), Position::new(0, 1), " "),
+                (Position::new(0, 2), Position::new(0, 2), " "),
+                (Position::new(0, 3), Position::new(0, 3), " "),
+                (Position::new(1, 8), Position::new(1, 8), " "),
+            ],
+            edits
+                .iter()
+                .map(|edit| (edit.range.start, edit.range.end, edit.new_text.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(minimal_edits("a  b", "a  b").is_empty());
+
+        // Only the indentation that goes away is touched, not the line break
+        // before it, so the end of the line above stays where it is
+        let edits = minimal_edits("{\n      a = 1;\n}", "{\n  a = 1;\n}");
+        assert_eq!(
+            vec![(Position::new(1, 2), Position::new(1, 6), "")],
+            edits
+                .iter()
+                .map(|edit| (edit.range.start, edit.range.end, edit.new_text.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        let edits = minimal_edits("[ 1 2 ]", "[ 1 3 ]");
+        assert_eq!(1, edits.len());
+        assert_eq!(Position::new(0, 4), edits[0].range.start);
+        assert_eq!("3", edits[0].new_text);
+    }
+
+    #[test]
+    fn test_formatter_from_settings() {
+        assert_eq!(Formatter::NixpkgsFmt, Formatter::from_settings(&Value::Null));
+        assert_eq!(
+            Formatter::NixpkgsFmt,
+            Formatter::from_settings(&serde_json::json!("nixpkgs-fmt"))
+        );
+        assert_eq!(
+            Formatter::External {
+                command: vec![String::from("nixfmt")],
+                timeout: DEFAULT_TIMEOUT,
+            },
+            Formatter::from_settings(&serde_json::json!("nixfmt"))
+        );
+        assert_eq!(
+            Formatter::External {
+                command: vec![String::from("alejandra"), String::from("--quiet")],
+                timeout: Duration::from_millis(500),
+            },
+            Formatter::from_settings(&serde_json::json!({
+                "command": ["alejandra", "--quiet"],
+                "timeout": 500,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_on_type_formatting() {
+        let on_type = |code: &str, offset: usize, ch: &str| {
+            on_type_edits(code, &rnix::parse(code).node(), offset, ch)
+                .unwrap()
+                .into_iter()
+                .map(|edit| (edit.range.start, edit.range.end, edit.new_text))
+                .collect::<Vec<_>>()
+        };
+
+        let code = "{\n  a = 1;\n\n}";
+        assert_eq!(
+            vec![(Position::new(2, 0), Position::new(2, 0), String::from("  "))],
+            on_type(code, code.len() - 2, "\n")
+        );
+        let code = "let\n  a = 1;\nin a";
+        assert!(on_type(code, code.len() - 4, "\n").is_empty());
+
+        let code = "{\n      a = 1;\n}";
+        assert_eq!(
+            vec![(Position::new(1, 2), Position::new(1, 6), String::new())],
+            on_type(code, code.find(';').unwrap() + 1, ";")
+        );
+    }
+
+    #[test]
+    fn test_reindent_nested_node() {
+        let code = "{\n  a = {\n    b = 1;\n  };\n}";
+        let offset = code.find("{\n    b").unwrap();
+        let indent = line_indent(code, offset);
+        assert_eq!("  ", indent);
+        assert_eq!("{\n    b = 1;\n  }", reindent("{\n  b = 1;\n}", indent));
+    }
+}
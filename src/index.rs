@@ -0,0 +1,225 @@
+use crate::{symbols, utils};
+use log::{trace, warn};
+use lsp_types::{DocumentSymbol, Location, Range, SymbolInformation, Url};
+use rnix::types::{Pattern, TypedNode};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// The most symbols a single `workspace/symbol` query returns
+const MAX_RESULTS: usize = 256;
+
+#[derive(Debug)]
+enum Job {
+    Scan(PathBuf),
+    Update(PathBuf),
+    Remove(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct IndexedSymbol {
+    /// The full attribute path, like `services.myapp.enable`
+    qualified: String,
+    info: SymbolInformation,
+}
+
+/// Symbols of every `.nix` file below the workspace roots, including files
+/// that were never opened. The files are read and parsed on a background
+/// thread, so queries only see what has been indexed so far.
+#[derive(Debug)]
+pub struct WorkspaceIndex {
+    files: Arc<Mutex<HashMap<PathBuf, Vec<IndexedSymbol>>>>,
+    jobs: Sender<Job>,
+}
+
+impl WorkspaceIndex {
+    pub fn new() -> Self {
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let (jobs, receiver) = mpsc::channel();
+
+        let worker_files = Arc::clone(&files);
+        thread::spawn(move || {
+            for job in receiver {
+                trace!("Index job: {:?}", job);
+                match job {
+                    Job::Scan(root) => scan(&worker_files, &root),
+                    Job::Update(path) => index_file(&worker_files, &path),
+                    Job::Remove(path) => {
+                        worker_files.lock().unwrap().remove(&path);
+                    }
+                }
+            }
+        });
+
+        Self { files, jobs }
+    }
+    /// Indexes every `.nix` file in the directory, recursively
+    pub fn scan(&self, root: PathBuf) {
+        let _ = self.jobs.send(Job::Scan(root));
+    }
+    /// Re-indexes a file that was created or changed on disk
+    pub fn update(&self, path: PathBuf) {
+        let _ = self.jobs.send(Job::Update(path));
+    }
+    /// Forgets a file that was deleted from disk
+    pub fn remove(&self, path: PathBuf) {
+        let _ = self.jobs.send(Job::Remove(path));
+    }
+    pub fn search(&self, query: &str) -> Vec<SymbolInformation> {
+        let query = query.to_lowercase();
+        let files = self.files.lock().unwrap();
+
+        let mut matches = files
+            .values()
+            .flatten()
+            .filter(|symbol| symbol.qualified.to_lowercase().contains(&query))
+            .collect::<Vec<_>>();
+        // Exact names first, then names that start with the query,
+        // then anything else that merely contains it.
+        matches.sort_by_key(|symbol| {
+            let name = symbol.info.name.to_lowercase();
+            (
+                name != query,
+                !name.starts_with(&query),
+                symbol.qualified.len(),
+            )
+        });
+        matches
+            .into_iter()
+            .take(MAX_RESULTS)
+            .map(|symbol| symbol.info.clone())
+            .collect()
+    }
+}
+
+fn scan(files: &Mutex<HashMap<PathBuf, Vec<IndexedSymbol>>>, dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Could not index {}: {}", dir.display(), err);
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // Symlinks are skipped so that `result` links into the store
+        // don't pull in half of nixpkgs.
+        match entry.file_type() {
+            Ok(kind) if kind.is_symlink() || hidden => (),
+            Ok(kind) if kind.is_dir() => scan(files, &path),
+            Ok(kind) if kind.is_file() && path.extension().map_or(false, |ext| ext == "nix") => {
+                index_file(files, &path)
+            }
+            _ => (),
+        }
+    }
+}
+
+fn index_file(files: &Mutex<HashMap<PathBuf, Vec<IndexedSymbol>>>, path: &Path) {
+    let (code, uri) = match (fs::read_to_string(path), Url::from_file_path(path)) {
+        (Ok(code), Ok(uri)) => (code, uri),
+        _ => {
+            files.lock().unwrap().remove(path);
+            return;
+        }
+    };
+    files
+        .lock()
+        .unwrap()
+        .insert(path.to_owned(), symbols_in(&uri, &code));
+}
+
+fn symbols_in(uri: &Url, code: &str) -> Vec<IndexedSymbol> {
+    let ast = rnix::parse(code);
+    let root = ast.node();
+
+    // Every file has its own `pkgs` and `lib` arguments, they would drown
+    // out the interesting results. What their defaults define is kept.
+    let arguments = root
+        .descendants()
+        .filter_map(Pattern::cast)
+        .flat_map(|pattern| {
+            pattern
+                .entries()
+                .filter_map(|entry| entry.name())
+                .chain(pattern.at())
+                .collect::<Vec<_>>()
+        })
+        .map(|name| utils::range(code, name.node().text_range()))
+        .collect::<Vec<_>>();
+
+    let mut indexed = Vec::new();
+    flatten(
+        uri,
+        &[],
+        symbols::document_symbols(code, &root),
+        &arguments,
+        &mut indexed,
+    );
+    indexed
+}
+
+fn flatten(
+    uri: &Url,
+    container: &[&str],
+    outline: Vec<DocumentSymbol>,
+    arguments: &[Range],
+    indexed: &mut Vec<IndexedSymbol>,
+) {
+    for symbol in outline {
+        if arguments.contains(&symbol.selection_range) {
+            if let Some(children) = symbol.children {
+                flatten(uri, container, children, arguments, indexed);
+            }
+            continue;
+        }
+        let mut path = container.to_vec();
+        path.push(&symbol.name);
+        let qualified = path.join(".");
+
+        indexed.push(IndexedSymbol {
+            qualified,
+            info: SymbolInformation {
+                name: symbol.name.clone(),
+                kind: symbol.kind,
+                deprecated: None,
+                location: Location {
+                    uri: uri.clone(),
+                    range: symbol.range,
+                },
+                container_name: if container.is_empty() {
+                    None
+                } else {
+                    Some(container.join("."))
+                },
+            },
+        });
+        if let Some(children) = symbol.children {
+            flatten(uri, &path, children, arguments, indexed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbols_in() {
+        let uri = Url::parse("file:///default.nix").unwrap();
+        let code = "{ pkgs, lib ? let helper = 1; in helper }@args: { package = 1; }";
+        let names = symbols_in(&uri, code)
+            .into_iter()
+            .map(|symbol| symbol.qualified)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["helper", "package"], names);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{diagnostics::CheckContext, utils};
+use lazy_static::lazy_static;
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString};
+use regex::Regex;
+use rnix::{types::*, NodeOrToken, SyntaxKind, SyntaxNode};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+
+lazy_static! {
+    static ref ALLOW: Regex = Regex::new(r"rnix-lsp:\s*allow\(([^)]*)\)").unwrap();
+}
+
+/// A lint rule. The name is what configuration and `allow` comments refer
+/// to, the code is shown to users and never changes.
+pub struct Rule {
+    pub name: &'static str,
+    pub code: &'static str,
+    pub severity: DiagnosticSeverity,
+    check: fn(&CheckContext, &mut Vec<(SyntaxNode, String)>),
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "useless-parens",
+        code: "L001",
+        severity: DiagnosticSeverity::Hint,
+        check: useless_parens,
+    },
+    Rule {
+        name: "redundant-if",
+        code: "L002",
+        severity: DiagnosticSeverity::Warning,
+        check: redundant_if,
+    },
+    Rule {
+        name: "null-check-with-default",
+        code: "L003",
+        severity: DiagnosticSeverity::Warning,
+        check: null_check_with_default,
+    },
+    Rule {
+        name: "empty-let",
+        code: "L004",
+        severity: DiagnosticSeverity::Warning,
+        check: empty_let,
+    },
+    Rule {
+        name: "useless-rec",
+        code: "L005",
+        severity: DiagnosticSeverity::Warning,
+        check: useless_rec,
+    },
+    Rule {
+        name: "redundant-inherit",
+        code: "L006",
+        severity: DiagnosticSeverity::Warning,
+        check: redundant_inherit,
+    },
+];
+
+/// Runs every rule that isn't turned off, skipping findings that an
+/// `# rnix-lsp: allow(rule-name)` comment asks to ignore.
+pub fn lints(context: &CheckContext, diagnostics: &mut Vec<Diagnostic>) -> Option<()> {
+    let allowed = allow_comments(context.code, &context.ast.node());
+    for rule in RULES {
+        let configured = context
+            .config
+            .lints
+            .get(rule.name)
+            .or_else(|| context.config.lints.get(rule.code));
+        let severity = match configured {
+            Some(Some(severity)) => *severity,
+            Some(None) => continue,
+            None => rule.severity,
+        };
+
+        let mut findings = Vec::new();
+        (rule.check)(context, &mut findings);
+        for (node, message) in findings {
+            let line =
+                utils::offset_to_pos(context.code, usize::from(node.text_range().start())).line;
+            let suppressed = allowed.get(&line).map_or(false, |names| {
+                names.contains(rule.name) || names.contains(rule.code)
+            });
+            if suppressed {
+                continue;
+            }
+            let mut diagnostic = context.diagnostic(&node, severity, message);
+            diagnostic.code = Some(NumberOrString::String(rule.code.into()));
+            diagnostics.push(diagnostic);
+        }
+    }
+    Some(())
+}
+
+/// Maps lines to the rules allowed on them. An `allow` comment at the end
+/// of a line applies to that line, one on a line of its own to the next.
+fn allow_comments(code: &str, root: &SyntaxNode) -> HashMap<u64, HashSet<String>> {
+    let mut allowed = HashMap::new();
+    let comments = root
+        .descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+        .filter(|token| token.kind() == SyntaxKind::TOKEN_COMMENT);
+    for comment in comments {
+        for captures in ALLOW.captures_iter(comment.text().as_str()) {
+            let start = usize::from(comment.text_range().start());
+            let line_start = code[..start].rfind('\n').map_or(0, |n| n + 1);
+            let mut line = utils::offset_to_pos(code, start).line;
+            if code[line_start..start].trim().is_empty() {
+                line += 1;
+            }
+            allowed
+                .entry(line)
+                .or_insert_with(HashSet::new)
+                .extend(captures[1].split(',').map(|name| name.trim().to_owned()));
+        }
+    }
+    allowed
+}
+
+/// `(x)`, `([ 1 ])` and `a = (f x);` need no parentheses
+fn useless_parens(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    for paren in context.ast.node().descendants().filter_map(Paren::cast) {
+        let inner = match paren.inner() {
+            Some(inner) => inner,
+            None => continue,
+        };
+        let atomic = match inner.kind() {
+            SyntaxKind::NODE_ATTR_SET
+            | SyntaxKind::NODE_IDENT
+            | SyntaxKind::NODE_LIST
+            | SyntaxKind::NODE_PAREN
+            | SyntaxKind::NODE_STRING
+            | SyntaxKind::NODE_VALUE => true,
+            _ => false,
+        };
+        let parent = paren.node().parent();
+        let standalone = parent.as_ref().map_or(false, |parent| match parent.kind() {
+            SyntaxKind::NODE_ROOT | SyntaxKind::NODE_PAREN => true,
+            SyntaxKind::NODE_KEY_VALUE => KeyValue::cast(parent.clone())
+                .and_then(|entry| entry.value())
+                .map_or(false, |value| value == *paren.node()),
+            SyntaxKind::NODE_LET_IN => LetIn::cast(parent.clone())
+                .and_then(|let_in| let_in.body())
+                .map_or(false, |body| body == *paren.node()),
+            _ => false,
+        });
+        if atomic || standalone {
+            findings.push((
+                paren.node().clone(),
+                String::from("unnecessary parentheses"),
+            ));
+        }
+    }
+}
+
+/// `if c then true else false` is just `c`
+fn redundant_if(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    let as_bool = |node: Option<SyntaxNode>| {
+        let ident = node.and_then(Ident::cast)?;
+        let unshadowed = utils::scope_for(context.file, ident.node().clone())
+            .map_or(true, |scope| !scope.contains_key(ident.as_str()));
+        match ident.as_str() {
+            "true" if unshadowed => Some(true),
+            "false" if unshadowed => Some(false),
+            _ => None,
+        }
+    };
+    for if_else in context.ast.node().descendants().filter_map(IfElse::cast) {
+        let condition = match if_else.condition() {
+            Some(condition) => condition.text().to_string(),
+            None => continue,
+        };
+        let message = match (as_bool(if_else.body()), as_bool(if_else.else_body())) {
+            (Some(true), Some(false)) => format!("this is the same as `{}`", condition),
+            (Some(false), Some(true)) => format!("this is the same as `!({})`", condition),
+            _ => continue,
+        };
+        findings.push((if_else.node().clone(), message));
+    }
+}
+
+/// `x == null` where `x` is a pattern argument with a non-null default only
+/// catches callers that pass `null` explicitly, which is rarely the intent.
+fn null_check_with_default(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    for operation in context.ast.node().descendants().filter_map(BinOp::cast) {
+        match operation.operator() {
+            BinOpKind::Equal | BinOpKind::NotEqual => (),
+            _ => continue,
+        }
+        let (lhs, rhs) = match (operation.lhs(), operation.rhs()) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => continue,
+        };
+        let is_null = |node: &SyntaxNode| node.text() == "null";
+        let ident = match (Ident::cast(lhs.clone()), Ident::cast(rhs.clone())) {
+            (Some(ident), _) if is_null(&rhs) => ident,
+            (_, Some(ident)) if is_null(&lhs) => ident,
+            _ => continue,
+        };
+        let default = utils::binding_for(context.file, &ident)
+            .filter(|var| var.datatype == utils::Datatype::Lambda)
+            .and_then(|var| var.key.parent())
+            .and_then(PatEntry::cast)
+            .and_then(|entry| entry.default());
+        if let Some(default) = default {
+            if !is_null(&default) {
+                findings.push((
+                    operation.node().clone(),
+                    format!(
+                        "`{}` defaults to `{}`, so this only checks for an explicit `null`",
+                        ident.as_str(),
+                        default.text()
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// `let in x` is just `x`
+fn empty_let(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    for let_in in context.ast.node().descendants().filter_map(LetIn::cast) {
+        if let_in.entries().next().is_none() && let_in.inherits().next().is_none() {
+            findings.push((
+                let_in.node().clone(),
+                String::from("`let` without bindings"),
+            ));
+        }
+    }
+}
+
+/// A `rec` set whose attributes never refer to each other
+fn useless_rec(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    for set in context.ast.node().descendants().filter_map(AttrSet::cast) {
+        if !set.recursive()
+            || set
+                .node()
+                .descendants()
+                .any(|node| node.kind() == SyntaxKind::NODE_ERROR)
+        {
+            continue;
+        }
+        let scope = match utils::scope_for(context.file, set.node().clone()) {
+            Some(scope) => scope,
+            None => continue,
+        };
+        let self_referencing = scope
+            .values()
+            .filter(|var| var.set == *set.node())
+            .any(|var| !utils::references_to(context.file, var).is_empty());
+        if !self_referencing {
+            findings.push((
+                set.node().clone(),
+                String::from("`rec` is unnecessary, no attribute refers to another"),
+            ));
+        }
+    }
+}
+
+/// `inherit a;` in a `let` binds `a` to itself, it was already in scope.
+/// Inside a `with`, names that aren't bound lexically come from the `with`,
+/// and inheriting them does change how later lookups resolve.
+fn redundant_inherit(context: &CheckContext, findings: &mut Vec<(SyntaxNode, String)>) {
+    for inherit in context.ast.node().descendants().filter_map(Inherit::cast) {
+        let let_node = match inherit.node().parent() {
+            Some(parent)
+                if parent.kind() == SyntaxKind::NODE_LET_IN
+                    || parent.kind() == SyntaxKind::NODE_LEGACY_LET =>
+            {
+                parent
+            }
+            _ => continue,
+        };
+        let lexical = inherit.idents().all(|ident| {
+            utils::binding_for(context.file, &ident).map_or(false, |var| var.set != let_node)
+        });
+        if inherit.from().is_none() && lexical {
+            findings.push((
+                inherit.node().clone(),
+                String::from("inheriting into a `let` from the surrounding scope does nothing"),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn lint(code: &str, config: &Config) -> Vec<(String, String)> {
+        let context = CheckContext {
+            config,
+            ..CheckContext::for_test(code, None, None)
+        };
+        let mut diagnostics = Vec::new();
+        lints(&context, &mut diagnostics);
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| match diagnostic.code {
+                Some(NumberOrString::String(code)) => (code, diagnostic.message),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let code = "{ a ? 1, b ? null }: let
+  x = (a);
+  y = if a == null then true else false;
+  z = b == null;
+  inherit b;
+  s = rec { c = 1; d = 2; };
+  t = rec { e = 1; f = e; };
+in let in [ x y z s t (f a) ]";
+        let codes = lint(code, &Config::default())
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["L001", "L002", "L003", "L004", "L005", "L006"], codes);
+    }
+
+    #[test]
+    fn test_redundant_inherit() {
+        let codes = |code: &str| {
+            lint(code, &Config::default())
+                .into_iter()
+                .map(|(code, _)| code)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["L006"],
+            codes("hello: with pkgs; let inherit hello; in hello")
+        );
+        assert!(codes("with pkgs; let inherit hello; in hello").is_empty());
+        assert!(codes("hello: with pkgs; let inherit hello gcc; in [ hello gcc ]").is_empty());
+    }
+
+    #[test]
+    fn test_suppression() {
+        let code = "[\n  # rnix-lsp: allow(useless-parens)\n  (a)\n  (b) # rnix-lsp: allow(L001)\n  (c)\n]";
+        assert_eq!(1, lint(code, &Config::default()).len());
+
+        let mut config = Config::default();
+        config.lints.insert(String::from("useless-parens"), None);
+        assert!(lint("(a)", &config).is_empty());
+    }
+}
@@ -3,7 +3,9 @@ use crate::{
     App,
 };
 use lsp_types::Url;
-use rnix::{types::*, value::Value as ParsedValue, NodeOrToken, SyntaxKind, SyntaxNode};
+use rnix::{
+    parser::AST, types::*, value::Value as ParsedValue, NodeOrToken, SyntaxKind, SyntaxNode,
+};
 use std::{
     collections::{hash_map::Entry, HashMap},
     convert::TryFrom,
@@ -31,6 +33,99 @@ lazy_static! {
     ].into_iter().map(String::from).collect::<Vec<_>>();
 }
 
+/// Builtins that are in scope everywhere, without going through `builtins.`
+pub const GLOBAL_BUILTINS: &[&str] = &[
+    "abort", "baseNameOf", "builtins", "derivation", "dirOf", "false", "fetchGit",
+    "fetchMercurial", "fetchTarball", "fromTOML", "import", "isNull", "map", "null",
+    "placeholder", "removeAttrs", "scopedImport", "throw", "toString", "true",
+];
+
+/// Returns the name of the builtin an expression refers to, either through
+/// `builtins.name` or directly for one of the `GLOBAL_BUILTINS`. Builtins
+/// shadowed by a local binding don't count.
+pub fn builtin_name(file: &Rc<Url>, node: &SyntaxNode) -> Option<String> {
+    let unshadowed = |ident: &Ident| {
+        utils::scope_for(file, ident.node().clone())
+            .map_or(true, |scope| !scope.contains_key(ident.as_str()))
+    };
+    if let Some(ident) = Ident::cast(node.clone()) {
+        if GLOBAL_BUILTINS.contains(&ident.as_str()) && unshadowed(&ident) {
+            return Some(ident.as_str().into());
+        }
+    } else if let Some(select) = Select::cast(node.clone()) {
+        let set = select.set().and_then(Ident::cast)?;
+        if set.as_str() == "builtins" && unshadowed(&set) {
+            return Some(Ident::cast(select.index()?)?.as_str().into());
+        }
+    }
+    None
+}
+
+/// Whether `node` is a file being imported: the argument of `import` or
+/// `callPackage`, or an entry of a module's `imports = [ ... ]`.
+pub fn is_import_target(file: &Rc<Url>, node: &SyntaxNode) -> bool {
+    let parent = match node.parent() {
+        Some(parent) => parent,
+        None => return false,
+    };
+    if let Some(apply) = Apply::cast(parent.clone()) {
+        if apply.value().as_ref() != Some(node) {
+            return false;
+        }
+        let function = match apply.lambda() {
+            Some(function) => function,
+            None => return false,
+        };
+        if builtin_name(file, &function).as_deref() == Some("import") {
+            return true;
+        }
+        let name = match Select::cast(function.clone()) {
+            Some(select) => select.index().and_then(Ident::cast),
+            None => Ident::cast(function),
+        };
+        return name.map_or(false, |name| {
+            name.as_str() == "callPackage" || name.as_str() == "callPackages"
+        });
+    }
+    parent.kind() == SyntaxKind::NODE_LIST
+        && parent
+            .parent()
+            .and_then(KeyValue::cast)
+            .and_then(|entry| entry.key())
+            .and_then(|key| key.path().last())
+            .map_or(false, |name| name.text() == "imports")
+}
+
+/// Moves `file` over to the file `path` points to from it, and returns the
+/// expression at the top of that file. A directory stands for its
+/// `default.nix`. Files are parsed once, and kept in `files`.
+pub fn imported_root(
+    files: &mut HashMap<Url, (AST, String)>,
+    file: &mut Rc<Url>,
+    path: &str,
+) -> Option<SyntaxNode> {
+    // TODO use anchor
+    *file = Rc::new(file.join(path).ok()?);
+    let mut path = utils::uri_path(&file)?;
+    if path.is_dir() {
+        path.push("default.nix");
+        *file = Rc::new(Url::from_file_path(&path).ok()?);
+    }
+    match files.entry((**file).clone()) {
+        Entry::Occupied(entry) => {
+            let (ast, _code) = entry.get();
+            Some(ast.root().inner()?.clone())
+        }
+        Entry::Vacant(placeholder) => {
+            let content = fs::read_to_string(&path).ok()?;
+            let ast = rnix::parse(&content);
+            let node = ast.root().inner()?.clone();
+            placeholder.insert((ast, content));
+            Some(node)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LSPDetails {
     pub datatype: Datatype,
@@ -38,6 +133,7 @@ pub struct LSPDetails {
     pub documentation: Option<String>,
     pub deprecated: bool,
     pub params: Option<String>,
+    pub args: Option<Vec<String>>,
 }
 
 impl LSPDetails {
@@ -48,16 +144,18 @@ impl LSPDetails {
             documentation: None,
             deprecated: false,
             params: None,
+            args: None,
         }
     }
 
-    fn builtin_with_doc(deprecated: bool, params: Option<String>, documentation: String) -> LSPDetails {
+    fn builtin_with_doc(deprecated: bool, args: Option<Vec<String>>, documentation: String) -> LSPDetails {
         LSPDetails {
             datatype: Datatype::Lambda,
             var: None,
             documentation: Some(documentation),
             deprecated,
-            params,
+            params: args.as_ref().map(|args| args.join(" -> ")),
+            args,
         }
     }
 
@@ -68,6 +166,24 @@ impl LSPDetails {
             documentation: None,
             deprecated: false,
             params: None,
+            args: None,
+        }
+    }
+
+    /// What the documentation of a deprecated builtin says to use instead,
+    /// which is the paragraph after the `**DEPRECATED.**` marker.
+    pub fn deprecation(&self) -> Option<String> {
+        if !self.deprecated {
+            return None;
+        }
+        let doc = self.documentation.as_ref()?;
+        let note = doc.trim_start().trim_start_matches("**DEPRECATED.**");
+        let paragraph = note.trim_start().split("\n\n").next()?;
+        let paragraph = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+        if paragraph.is_empty() {
+            None
+        } else {
+            Some(paragraph)
         }
     }
 
@@ -143,22 +259,7 @@ impl App {
                 },
             };
 
-            // TODO use anchor
-            *file = Rc::new(file.join(&path).ok()?);
-            let path = utils::uri_path(&file)?;
-            node = match self.files.entry((**file).clone()) {
-                Entry::Occupied(entry) => {
-                    let (ast, _code) = entry.get();
-                    ast.root().inner()?.clone()
-                }
-                Entry::Vacant(placeholder) => {
-                    let content = fs::read_to_string(&path).ok()?;
-                    let ast = rnix::parse(&content);
-                    let node = ast.root().inner()?.clone();
-                    placeholder.insert((ast, content));
-                    node
-                }
-            };
+            node = imported_root(&mut self.files, file, &path)?;
         }
 
         if let Some(set) = AttrSet::cast(node) {
@@ -263,6 +364,16 @@ impl App {
         path
     }
 
+    /// Like `load_builtins`, but only asks `nix` once per session
+    pub fn builtins(&mut self) -> Rc<HashMap<String, LSPDetails>> {
+        if let Some(builtins) = &self.builtins {
+            return Rc::clone(builtins);
+        }
+        let builtins = Rc::new(self.load_builtins());
+        self.builtins = Some(Rc::clone(&builtins));
+        builtins
+    }
+
     fn fallback_builtins(&self, list: Vec<String>) -> HashMap<String, LSPDetails> {
         list.into_iter().map(|x| (x, LSPDetails::builtin_fallback())).collect::<HashMap<_, _>>()
     }
@@ -292,7 +403,7 @@ impl App {
                                     (String::from(x), LSPDetails::builtin_with_doc(
                                         doc.starts_with("**DEPRECATED.**"),
                                         // FIXME make sure that `lib.flip` is taken into account here
-                                        v["args"].as_array().map(|x| x.iter().map(|y| y.as_str().unwrap()).collect::<Vec<_>>().join(" -> ")),
+                                        v["args"].as_array().map(|x| x.iter().map(|y| String::from(y.as_str().unwrap())).collect::<Vec<_>>()),
                                         doc
                                     ))
                                 })
@@ -21,11 +21,25 @@
     clippy::integer_arithmetic,
 )]
 
+mod builtin_args;
+mod code_actions;
 mod completion;
+mod config;
+mod diagnostics;
+mod folding;
+mod formatting;
+mod index;
+mod lints;
 mod lookup;
+mod references;
+mod semantic;
+mod signature;
+mod symbols;
 mod utils;
 
-use dirs::home_dir;
+use config::Config;
+use index::WorkspaceIndex;
+use lookup::LSPDetails;
 use itertools::Itertools;
 use log::{error, trace, warn};
 use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
@@ -44,13 +58,13 @@ use nixpkgs_tree_docsource::NixpkgsTreeDatabase;
 use rnix::{
     parser::*,
     types::*,
-    value::{Anchor as RAnchor, Value as RValue},
-    SyntaxNode, TextRange, TextSize,
+    value::Value as RValue,
+    SyntaxNode,
 };
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fs, panic,
-    path::{Path, PathBuf},
+    path::PathBuf,
     process,
     rc::Rc,
 };
@@ -74,7 +88,7 @@ fn real_main() -> Result<(), Error> {
     }));
 
     let (connection, io_threads) = Connection::stdio();
-    let capabilities = serde_json::to_value(&ServerCapabilities {
+    let mut capabilities = serde_json::to_value(&ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Options(
             TextDocumentSyncOptions {
                 open_close: Some(true),
@@ -82,39 +96,89 @@ fn real_main() -> Result<(), Error> {
                 ..TextDocumentSyncOptions::default()
             },
         )),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
         completion_provider: Some(CompletionOptions {
+            resolve_provider: Some(true),
             ..CompletionOptions::default()
         }),
         definition_provider: Some(true),
         document_formatting_provider: Some(true),
+        document_range_formatting_provider: Some(true),
+        document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+            first_trigger_character: String::from("}"),
+            more_trigger_character: Some(vec![String::from(";"), String::from("\n")]),
+        }),
+        document_highlight_provider: Some(true),
+        document_symbol_provider: Some(true),
         document_link_provider: Some(DocumentLinkOptions {
             resolve_provider: Some(false),
             work_done_progress_options: WorkDoneProgressOptions::default(),
         }),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        references_provider: Some(true),
         rename_provider: Some(RenameProviderCapability::Simple(true)),
         selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec![String::from(" "), String::from("(")]),
+            ..SignatureHelpOptions::default()
+        }),
+        workspace_symbol_provider: Some(true),
         ..ServerCapabilities::default()
     })
     .unwrap();
+    capabilities["semanticTokensProvider"] = semantic::capability();
 
-    connection.initialize(capabilities)?;
+    let init_params = connection.initialize(capabilities)?;
+
+    let index = WorkspaceIndex::new();
+    for root in workspace_roots(&init_params) {
+        index.scan(root);
+    }
 
     let (cache_invalid, manix_values) = load_manix_values().unwrap();
     let manix_options = load_manix_options(cache_invalid).unwrap();
-    App {
+    let mut app = App {
         files: HashMap::new(),
+        open: HashSet::new(),
+        index,
+        config: Config::from_settings(&init_params["initializationOptions"]),
+        builtins: None,
         manix_options,
         manix_values,
+        package_meta: completion::PackageMetaCache::default(),
         conn: connection,
+    };
+    // Clients that can't register for file events dynamically would only
+    // answer with an error
+    let can_watch = init_params["capabilities"]["workspace"]["didChangeWatchedFiles"]
+        ["dynamicRegistration"]
+        .as_bool()
+        .unwrap_or(false);
+    if can_watch {
+        app.watch_nix_files()?;
     }
-    .main();
+    app.main();
 
     io_threads.join()?;
 
     Ok(())
 }
 
+/// Reads the directories to index from the `initialize` request, falling
+/// back to the deprecated `rootUri` for clients without workspace folders.
+fn workspace_roots(params: &serde_json::Value) -> Vec<PathBuf> {
+    let folders = params["workspaceFolders"]
+        .as_array()
+        .map(|folders| folders.iter().map(|folder| &folder["uri"]).collect())
+        .unwrap_or_else(|| vec![&params["rootUri"]]);
+    folders
+        .into_iter()
+        .filter_map(|uri| Url::parse(uri.as_str()?).ok())
+        .filter_map(|uri| utils::uri_path(&uri))
+        .collect()
+}
+
 fn build_source_and_add<T>(
     mut source: T,
     name: &str,
@@ -233,8 +297,15 @@ fn load_manix_options(reload_cache: bool) -> Option<AggregateDocSource> {
 
 struct App {
     files: HashMap<Url, (AST, String)>,
+    /// The documents the client has open, as opposed to files that were
+    /// only read to resolve imports
+    open: HashSet<Url>,
+    index: WorkspaceIndex,
+    config: Config,
+    builtins: Option<Rc<HashMap<String, LSPDetails>>>,
     manix_options: manix::AggregateDocSource,
     manix_values: manix::AggregateDocSource,
+    package_meta: completion::PackageMetaCache,
     conn: Connection,
 }
 impl App {
@@ -249,6 +320,31 @@ impl App {
             .send(Message::Notification(notification))
             .unwrap();
     }
+    /// Asks the client to tell us about `.nix` files changing on disk, so
+    /// that the workspace index doesn't go stale.
+    fn watch_nix_files(&mut self) -> Result<(), Error> {
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: String::from("**/*.nix"),
+                kind: None,
+            }],
+        };
+        let params = RegistrationParams {
+            registrations: vec![Registration {
+                id: String::from("watch-nix-files"),
+                method: DidChangeWatchedFiles::METHOD.into(),
+                register_options: Some(serde_json::to_value(options)?),
+            }],
+        };
+        let request = Request::new(
+            RequestId::from(String::from("watch-nix-files")),
+            RegisterCapability::METHOD.into(),
+            params,
+        );
+        trace!("Sending request: {:#?}", request);
+        self.conn.sender.send(Message::Request(request))?;
+        Ok(())
+    }
     fn err<E>(&mut self, id: RequestId, err: E)
     where
         E: std::fmt::Display,
@@ -321,6 +417,9 @@ impl App {
                     range: None,
                 },
             ));
+        } else if let Some((id, params)) = cast::<SignatureHelpRequest>(&mut req) {
+            let signature_help = self.signature_help(&params.text_document_position_params);
+            self.reply(Response::new_ok(id, signature_help));
         } else if let Some((id, params)) = cast::<Completion>(&mut req) {
             // look at params.context for trigger reasons, etc
             let completions = self
@@ -328,6 +427,8 @@ impl App {
                 .unwrap_or_default();
             // .unwrap_or_else(|| CompletionResponse::Array(Vec::new()));
             self.reply(Response::new_ok(id, completions));
+        } else if let Some((id, item)) = cast::<ResolveCompletionItem>(&mut req) {
+            self.resolve_completion(id, item);
         } else if let Some((id, params)) = cast::<Rename>(&mut req) {
             let changes = self.rename(params);
             self.reply(Response::new_ok(
@@ -337,20 +438,46 @@ impl App {
                     ..WorkspaceEdit::default()
                 },
             ));
+        } else if let Some((id, params)) = cast::<References>(&mut req) {
+            let locations = self.references(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, locations));
+        } else if let Some((id, params)) = cast::<DocumentHighlightRequest>(&mut req) {
+            let highlights = self.document_highlight(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, highlights));
+        } else if let Some((id, params)) = cast::<DocumentSymbolRequest>(&mut req) {
+            let symbols = self.document_symbols(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, DocumentSymbolResponse::Nested(symbols)));
+        } else if let Some((id, params)) = cast::<WorkspaceSymbol>(&mut req) {
+            let symbols = self.index.search(&params.query);
+            self.reply(Response::new_ok(id, symbols));
+        } else if let Some((id, params)) = cast::<CodeActionRequest>(&mut req) {
+            let actions = self.code_actions(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, actions));
         } else if let Some((id, params)) = cast::<DocumentLinkRequest>(&mut req) {
             let document_links = self.document_links(&params).unwrap_or_default();
             self.reply(Response::new_ok(id, document_links));
         } else if let Some((id, params)) = cast::<Formatting>(&mut req) {
-            let changes = if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
-                let fmt = nixpkgs_fmt::reformat_node(&ast.node());
-                vec![TextEdit {
-                    range: utils::range(&code, TextRange::up_to(ast.node().text().len())),
-                    new_text: fmt.text().to_string(),
-                }]
-            } else {
-                Vec::new()
-            };
+            match self.formatting(&params) {
+                Ok(changes) => self.reply(Response::new_ok(id, changes)),
+                Err(err) => self.err(id, err),
+            }
+        } else if let Some((id, params)) = cast::<RangeFormatting>(&mut req) {
+            match self.range_formatting(&params) {
+                Ok(changes) => self.reply(Response::new_ok(id, changes)),
+                Err(err) => self.err(id, err),
+            }
+        } else if let Some((id, params)) = cast::<OnTypeFormatting>(&mut req) {
+            let changes = self.on_type_formatting(&params).unwrap_or_default();
             self.reply(Response::new_ok(id, changes));
+        } else if let Some((id, params)) = cast::<FoldingRangeRequest>(&mut req) {
+            let ranges = self.folding_ranges(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, ranges));
+        } else if let Some((id, params)) = cast::<semantic::SemanticTokensFull>(&mut req) {
+            let tokens = self.semantic_tokens(&params.text_document.uri, None);
+            self.reply(Response::new_ok(id, tokens));
+        } else if let Some((id, params)) = cast::<semantic::SemanticTokensRange>(&mut req) {
+            let tokens = self.semantic_tokens(&params.text_document.uri, Some(params.range));
+            self.reply(Response::new_ok(id, tokens));
         } else if let Some((id, params)) = cast::<SelectionRangeRequest>(&mut req) {
             let mut selections = Vec::new();
             if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
@@ -378,8 +505,13 @@ impl App {
                 let text = params.text_document.text;
                 let parsed = rnix::parse(&text);
                 self.send_diagnostics(params.text_document.uri.clone(), &text, &parsed)?;
+                self.open.insert(params.text_document.uri.clone());
                 self.files.insert(params.text_document.uri, (parsed, text));
             }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(req.params)?;
+                self.open.remove(&params.text_document.uri);
+            }
             DidChangeTextDocument::METHOD => {
                 // Per the language server spec (https://git.io/JcrvY), we should apply changes
                 // in order, the same as we would if we received them in separate notifications.
@@ -433,6 +565,21 @@ impl App {
                 self.files
                     .insert(uri, (parsed, content.to_owned().to_string()));
             }
+            DidChangeConfiguration::METHOD => {
+                let params: DidChangeConfigurationParams = serde_json::from_value(req.params)?;
+                self.change_configuration(params);
+            }
+            DidChangeWatchedFiles::METHOD => {
+                let params: DidChangeWatchedFilesParams = serde_json::from_value(req.params)?;
+                for change in params.changes {
+                    if let Some(path) = utils::uri_path(&change.uri) {
+                        match change.typ {
+                            FileChangeType::Deleted => self.index.remove(path),
+                            _ => self.index.update(path),
+                        }
+                    }
+                }
+            }
             _ => (),
         }
         Ok(())
@@ -531,29 +678,21 @@ impl App {
     }
     fn document_links(&mut self, params: &DocumentLinkParams) -> Option<Vec<DocumentLink>> {
         let (current_ast, current_content) = self.files.get(&params.text_document.uri)?;
-        let parent_dir = Path::new(params.text_document.uri.path()).parent();
-        let home_dir = home_dir();
-        let home_dir = home_dir.as_ref();
 
         let mut links = VecDeque::new();
         for node in current_ast.node().descendants() {
             let value = Value::cast(node.clone()).and_then(|v| v.to_value().ok());
             if let Some(RValue::Path(anchor, path)) = value {
-                let file_url = match anchor {
-                    RAnchor::Absolute => Some(PathBuf::from(&path)),
-                    RAnchor::Relative => parent_dir.map(|p| p.join(path)),
-                    RAnchor::Home => home_dir.map(|home| home.join(path)),
-                    RAnchor::Store => None,
-                }
-                .map(|path| {
-                    if path.is_dir() {
-                        path.join("default.nix")
-                    } else {
-                        path
-                    }
-                })
-                .filter(|path| path.is_file())
-                .and_then(|s| Url::parse(&format!("file://{}", s.to_string_lossy())).ok());
+                let file_url = utils::resolve_path(&params.text_document.uri, anchor, &path)
+                    .map(|path| {
+                        if path.is_dir() {
+                            path.join("default.nix")
+                        } else {
+                            path
+                        }
+                    })
+                    .filter(|path| path.is_file())
+                    .and_then(|s| Url::parse(&format!("file://{}", s.to_string_lossy())).ok());
 
                 if let Some(file_url) = file_url {
                     links.push_back((node.text_range(), file_url))
@@ -605,28 +744,12 @@ impl App {
         Some(lsp_links)
     }
     fn send_diagnostics(&mut self, uri: Url, code: &str, ast: &AST) -> Result<(), Error> {
-        let errors = ast.errors();
-        let mut diagnostics = Vec::with_capacity(errors.len());
-        for err in errors {
-            let node_range = match err {
-                ParseError::Unexpected(range)
-                | ParseError::UnexpectedDoubleBind(range)
-                | ParseError::UnexpectedExtra(range)
-                | ParseError::UnexpectedWanted(_, range, _) => Some(range),
-                ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_) => {
-                    Some(TextRange::at(TextSize::of(code), TextSize::from(0)))
-                }
-                _ => None,
-            };
-            if let Some(node_range) = node_range {
-                diagnostics.push(Diagnostic {
-                    range: utils::range(code, node_range),
-                    severity: Some(DiagnosticSeverity::Error),
-                    message: err.to_string(),
-                    ..Diagnostic::default()
-                });
-            }
-        }
+        let builtins = self.builtins();
+        let diagnostics = diagnostics::diagnostics(&uri, code, ast, &builtins, &self.config);
+        self.publish_diagnostics(uri, diagnostics);
+        Ok(())
+    }
+    fn publish_diagnostics(&mut self, uri: Url, diagnostics: Vec<Diagnostic>) {
         self.notify(Notification::new(
             "textDocument/publishDiagnostics".into(),
             PublishDiagnosticsParams {
@@ -635,6 +758,5 @@ impl App {
                 version: None,
             },
         ));
-        Ok(())
     }
 }
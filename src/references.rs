@@ -0,0 +1,61 @@
+use crate::{utils, App};
+use lsp_types::{
+    DocumentHighlight, DocumentHighlightKind, DocumentHighlightParams, Location, ReferenceParams,
+};
+use std::rc::Rc;
+
+impl App {
+    pub fn references(&self, params: &ReferenceParams) -> Option<Vec<Location>> {
+        let uri = &params.text_document_position.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.text_document_position.position)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        if !info.path.is_empty() {
+            // References to attributes of a set are not supported
+            return None;
+        }
+        let file = Rc::new(uri.clone());
+        let var = utils::binding_for(&file, &info.ident)?;
+
+        let mut locations = Vec::new();
+        if params.context.include_declaration {
+            locations.push(Location {
+                uri: uri.clone(),
+                range: utils::range(code, var.key.text_range()),
+            });
+        }
+        for ident in utils::references_to(&file, &var) {
+            locations.push(Location {
+                uri: uri.clone(),
+                range: utils::range(code, ident.node().text_range()),
+            });
+        }
+        Some(locations)
+    }
+    pub fn document_highlight(
+        &self,
+        params: &DocumentHighlightParams,
+    ) -> Option<Vec<DocumentHighlight>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let (ast, code) = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, params.text_document_position_params.position)?;
+        let info = utils::ident_at(&ast.node(), offset)?;
+        if !info.path.is_empty() {
+            return None;
+        }
+        let file = Rc::new(uri.clone());
+        let var = utils::binding_for(&file, &info.ident)?;
+
+        let mut highlights = vec![DocumentHighlight {
+            range: utils::range(code, var.key.text_range()),
+            kind: Some(DocumentHighlightKind::Write),
+        }];
+        for ident in utils::references_to(&file, &var) {
+            highlights.push(DocumentHighlight {
+                range: utils::range(code, ident.node().text_range()),
+                kind: Some(DocumentHighlightKind::Read),
+            });
+        }
+        Some(highlights)
+    }
+}
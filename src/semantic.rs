@@ -0,0 +1,212 @@
+use crate::{
+    lookup::{LSPDetails, GLOBAL_BUILTINS},
+    utils::{self, Datatype, Var},
+    App,
+};
+use lsp_types::{
+    request::Request, Range, SemanticToken, SemanticTokens, SemanticTokensParams,
+    SemanticTokensRangeParams, Url,
+};
+use rnix::{types::*, TextRange, TextSize};
+use std::{collections::HashMap, convert::TryFrom, rc::Rc};
+
+// The requests are spelled out by hand since lsp-types only knows the
+// draft names of the semantic token methods.
+#[derive(Debug)]
+pub enum SemanticTokensFull {}
+impl Request for SemanticTokensFull {
+    type Params = SemanticTokensParams;
+    type Result = Option<SemanticTokens>;
+    const METHOD: &'static str = "textDocument/semanticTokens/full";
+}
+#[derive(Debug)]
+pub enum SemanticTokensRange {}
+impl Request for SemanticTokensRange {
+    type Params = SemanticTokensRangeParams;
+    type Result = Option<SemanticTokens>;
+    const METHOD: &'static str = "textDocument/semanticTokens/range";
+}
+
+/// Indices into `TOKEN_TYPES`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TokenType {
+    Parameter,
+    Variable,
+    Property,
+    Function,
+    Namespace,
+    Unresolved,
+}
+const TOKEN_TYPES: &[&str] = &[
+    "parameter",
+    "variable",
+    "property",
+    "function",
+    "namespace",
+    "unresolvedReference",
+];
+
+/// Bits of `TOKEN_MODIFIERS`
+const DECLARATION: u32 = 1 << 0;
+const DEFAULT_LIBRARY: u32 = 1 << 1;
+const DEPRECATED: u32 = 1 << 2;
+const WITH_SCOPE: u32 = 1 << 3;
+const TOKEN_MODIFIERS: &[&str] = &["declaration", "defaultLibrary", "deprecated", "withScope"];
+
+/// The `semanticTokensProvider` server capability, in the shape LSP 3.16
+/// settled on.
+pub fn capability() -> serde_json::Value {
+    serde_json::json!({
+        "legend": {
+            "tokenTypes": TOKEN_TYPES,
+            "tokenModifiers": TOKEN_MODIFIERS,
+        },
+        "range": true,
+        "full": true,
+    })
+}
+
+impl App {
+    pub fn semantic_tokens(&mut self, uri: &Url, range: Option<Range>) -> Option<SemanticTokens> {
+        let builtins = self.builtins();
+        let (ast, code) = self.files.get(uri)?;
+        let range = match range {
+            Some(range) => Some(TextRange::new(
+                TextSize::try_from(utils::lookup_pos(code, range.start)?).ok()?,
+                TextSize::try_from(utils::lookup_pos(code, range.end)?).ok()?,
+            )),
+            None => None,
+        };
+        let file = Rc::new(uri.clone());
+
+        let mut data = Vec::new();
+        let (mut last_line, mut last_start) = (0, 0);
+        utils::walk_scopes(&file, &ast.node(), &HashMap::new(), &mut |ident, scope| {
+            let text_range = ident.node().text_range();
+            if let Some(range) = range {
+                if text_range.end() <= range.start() || text_range.start() >= range.end() {
+                    return;
+                }
+            }
+            let (token_type, modifiers) = classify(ident, scope, &builtins);
+
+            let position = utils::range(code, text_range);
+            let line = position.start.line as u32;
+            let start = position.start.character as u32;
+            data.push(SemanticToken {
+                delta_line: line - last_line,
+                delta_start: if line == last_line {
+                    start - last_start
+                } else {
+                    start
+                },
+                length: (position.end.character - position.start.character) as u32,
+                token_type: token_type as u32,
+                token_modifiers_bitset: modifiers,
+            });
+            last_line = line;
+            last_start = start;
+        });
+        Some(SemanticTokens {
+            result_id: None,
+            data,
+        })
+    }
+}
+
+/// Works out what an identifier refers to, given the variables in scope
+/// there, the same way the evaluator would: lexical bindings win over
+/// builtins, which win over anything a `with` may provide.
+fn classify(
+    ident: &Ident,
+    scope: &HashMap<String, Var>,
+    builtins: &HashMap<String, LSPDetails>,
+) -> (TokenType, u32) {
+    let name = ident.as_str();
+    let builtin_modifiers = || {
+        let deprecated = builtins.get(name).map_or(false, |details| details.deprecated);
+        DEFAULT_LIBRARY | if deprecated { DEPRECATED } else { 0 }
+    };
+
+    if !utils::is_variable_use(ident) {
+        if let Some(var) = scope.get(name).filter(|var| var.key == *ident.node()) {
+            return (from_datatype(var.datatype), DECLARATION);
+        }
+        let parent = ident.node().parent();
+        if let Some(select) = parent.clone().and_then(Select::cast) {
+            let from_builtins = select.set().and_then(Ident::cast).map_or(false, |set| {
+                set.as_str() == "builtins" && !scope.contains_key("builtins")
+            });
+            if from_builtins {
+                return (TokenType::Function, builtin_modifiers());
+            }
+            return (TokenType::Property, 0);
+        }
+        return (TokenType::Property, DECLARATION);
+    }
+
+    if let Some(var) = scope.get(name) {
+        (from_datatype(var.datatype), 0)
+    } else if GLOBAL_BUILTINS.contains(&name) {
+        let token_type = match name {
+            "builtins" => TokenType::Namespace,
+            "true" | "false" | "null" => TokenType::Variable,
+            _ => TokenType::Function,
+        };
+        (token_type, builtin_modifiers())
+    } else if utils::in_with_scope(ident.node()) {
+        (TokenType::Variable, WITH_SCOPE)
+    } else {
+        (TokenType::Unresolved, 0)
+    }
+}
+
+fn from_datatype(datatype: Datatype) -> TokenType {
+    match datatype {
+        Datatype::Lambda => TokenType::Parameter,
+        Datatype::Variable => TokenType::Variable,
+        Datatype::Attribute => TokenType::Property,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rnix::SyntaxKind;
+
+    #[test]
+    fn test_classify() {
+        let code = "x: let y = x; in rec { a = y; b = map; c = with z; w; d = q; e = a; f = builtins.toPath; inherit y; }";
+        let root = rnix::parse(code).node();
+        let file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let builtins = HashMap::new();
+        let mut classes = Vec::new();
+        utils::walk_scopes(&file, &root, &HashMap::new(), &mut |ident, scope| {
+            let parent = ident.node().parent();
+            if parent.map_or(true, |parent| parent.kind() != SyntaxKind::NODE_KEY) {
+                classes.push((ident.as_str().to_owned(), classify(ident, scope, &builtins)));
+            }
+        });
+        let expected = vec![
+            ("x", (TokenType::Parameter, DECLARATION)),
+            ("x", (TokenType::Parameter, 0)),
+            ("y", (TokenType::Variable, 0)),
+            ("map", (TokenType::Function, DEFAULT_LIBRARY)),
+            ("z", (TokenType::Unresolved, 0)),
+            ("w", (TokenType::Variable, WITH_SCOPE)),
+            ("q", (TokenType::Unresolved, 0)),
+            ("a", (TokenType::Property, 0)),
+            ("builtins", (TokenType::Namespace, DEFAULT_LIBRARY)),
+            ("toPath", (TokenType::Function, DEFAULT_LIBRARY)),
+            // Not the attribute it defines
+            ("y", (TokenType::Variable, 0)),
+        ];
+        assert_eq!(
+            expected
+                .into_iter()
+                .map(|(name, class)| (name.to_owned(), class))
+                .collect::<Vec<_>>(),
+            classes
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{lookup, utils, App};
+use lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel,
+    SignatureHelp, SignatureInformation, TextDocumentPositionParams,
+};
+use rnix::{types::*, SyntaxKind, SyntaxNode, TextSize};
+use std::{convert::TryFrom, rc::Rc};
+
+impl App {
+    pub fn signature_help(&mut self, params: &TextDocumentPositionParams) -> Option<SignatureHelp> {
+        let builtins = self.builtins();
+        let (ast, code) = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(code, params.position)?;
+        let (head, active) = application_at(&ast.node(), offset)?;
+        let file = Rc::new(params.text_document.uri.clone());
+
+        let (name, args, documentation) = if let Some(name) = lookup::builtin_name(&file, &head) {
+            let details = builtins.get(&name)?;
+            (name, details.args.clone()?, details.documentation.clone())
+        } else {
+            let ident = Ident::cast(head)?;
+            let var = utils::scope_for(&file, ident.node().clone())?.remove(ident.as_str())?;
+            (ident.as_str().to_owned(), lambda_params(var.value?), None)
+        };
+        if args.is_empty() {
+            return None;
+        }
+
+        let mut label = name;
+        let mut parameters = Vec::with_capacity(args.len());
+        for arg in args {
+            label.push(' ');
+            let start = label.encode_utf16().count();
+            label.push_str(&arg);
+            let end = label.encode_utf16().count();
+            parameters.push(ParameterInformation {
+                label: ParameterLabel::LabelOffsets([
+                    TryFrom::try_from(start).ok()?,
+                    TryFrom::try_from(end).ok()?,
+                ]),
+                documentation: None,
+            });
+        }
+        Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label,
+                documentation: documentation.map(|value| {
+                    Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    })
+                }),
+                parameters: Some(parameters),
+            }],
+            active_signature: Some(0),
+            active_parameter: TryFrom::try_from(active).ok(),
+        })
+    }
+}
+
+/// Finds the function applied at the cursor, along with the index of the
+/// argument being written. With the cursor after some whitespace, that is
+/// the argument after the last one applied so far.
+fn application_at(root: &SyntaxNode, offset: usize) -> Option<(SyntaxNode, usize)> {
+    let offset = TextSize::try_from(offset).ok()?;
+    let mut token = root.token_at_offset(offset).left_biased()?;
+    while token.kind() == SyntaxKind::TOKEN_WHITESPACE || token.kind() == SyntaxKind::TOKEN_COMMENT {
+        token = token.prev_token()?;
+    }
+    let end = token.text_range().end();
+
+    // Climb to the outermost application that ends right here
+    let mut expr = token.parent();
+    while let Some(parent) = expr.parent() {
+        let applicable = match parent.kind() {
+            SyntaxKind::NODE_APPLY
+            | SyntaxKind::NODE_ATTR_SET
+            | SyntaxKind::NODE_IDENT
+            | SyntaxKind::NODE_LIST
+            | SyntaxKind::NODE_PAREN
+            | SyntaxKind::NODE_SELECT
+            | SyntaxKind::NODE_STRING
+            | SyntaxKind::NODE_VALUE => true,
+            _ => false,
+        };
+        if !applicable || parent.text_range().end() != end {
+            break;
+        }
+        expr = parent;
+    }
+
+    let mut head = expr;
+    let mut applied = 0;
+    while let Some(apply) = Apply::cast(head.clone()) {
+        head = apply.lambda()?;
+        applied += 1;
+    }
+    if offset > end {
+        Some((head, applied))
+    } else if applied > 0 {
+        Some((head, applied - 1))
+    } else {
+        None
+    }
+}
+
+/// Lists the arguments of a curried lambda, with a pattern argument
+/// shown as a single `{ a, b ? default, ... }` parameter.
+fn lambda_params(mut node: SyntaxNode) -> Vec<String> {
+    let mut params = Vec::new();
+    loop {
+        if let Some(inner) = Paren::cast(node.clone()).and_then(|paren| paren.inner()) {
+            node = inner;
+            continue;
+        }
+        let lambda = match Lambda::cast(node) {
+            Some(lambda) => lambda,
+            None => break,
+        };
+        match lambda.arg().map(ParsedType::try_from) {
+            Some(Ok(ParsedType::Ident(ident))) => params.push(ident.as_str().to_owned()),
+            Some(Ok(ParsedType::Pattern(pattern))) => {
+                let mut entries = pattern
+                    .entries()
+                    .filter_map(|entry| {
+                        let name = entry.name()?.as_str().to_owned();
+                        Some(match entry.default() {
+                            Some(default) => format!("{} ? {}", name, default.text()),
+                            None => name,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                if pattern.ellipsis() {
+                    entries.push(String::from("..."));
+                }
+                params.push(format!("{{ {} }}", entries.join(", ")));
+            }
+            _ => break,
+        }
+        node = match lambda.body() {
+            Some(body) => body,
+            None => break,
+        };
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_application_at() {
+        let code = "builtins.elemAt xs ";
+        let root = rnix::parse(code).node();
+        let (head, active) = application_at(&root, code.len()).unwrap();
+        assert_eq!("builtins.elemAt", head.text().to_string());
+        assert_eq!(1, active);
+
+        let code = "f a";
+        let root = rnix::parse(code).node();
+        let (head, active) = application_at(&root, code.len()).unwrap();
+        assert_eq!("f", head.text().to_string());
+        assert_eq!(0, active);
+
+        let code = "f ";
+        let root = rnix::parse(code).node();
+        assert_eq!(0, application_at(&root, code.len()).unwrap().1);
+        assert!(application_at(&root, 1).is_none());
+    }
+
+    #[test]
+    fn test_lambda_params() {
+        let root = rnix::parse("a: { b, c ? 1, ... }: d: null").node();
+        let lambda = root.children().next().unwrap();
+        assert_eq!(vec!["a", "{ b, c ? 1, ... }", "d"], lambda_params(lambda));
+    }
+}
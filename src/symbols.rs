@@ -0,0 +1,183 @@
+use crate::{utils, App};
+use itertools::Itertools;
+use lsp_types::{DocumentSymbol, DocumentSymbolParams, SymbolKind};
+use rnix::{types::*, SyntaxKind, SyntaxNode};
+use std::convert::TryFrom;
+
+/// The detail given to symbols that are arguments of a lambda
+const ARGUMENT: &str = "argument";
+
+impl App {
+    pub fn document_symbols(&self, params: &DocumentSymbolParams) -> Option<Vec<DocumentSymbol>> {
+        let (ast, code) = self.files.get(&params.text_document.uri)?;
+        Some(document_symbols(code, &ast.node()))
+    }
+}
+
+/// Builds the outline of a file: let bindings, attribute set entries, lambda
+/// patterns and inherited names, nested the same way they are in the code.
+pub fn document_symbols(code: &str, root: &SyntaxNode) -> Vec<DocumentSymbol> {
+    let mut symbols = Vec::new();
+    collect(code, root, &mut symbols);
+    symbols
+}
+
+fn collect(code: &str, node: &SyntaxNode, symbols: &mut Vec<DocumentSymbol>) {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::LetIn(_)) | Ok(ParsedType::LegacyLet(_)) => {
+            collect_entries(code, node, SymbolKind::Variable, symbols)
+        }
+        Ok(ParsedType::AttrSet(_)) => collect_entries(code, node, SymbolKind::Property, symbols),
+        Ok(ParsedType::Lambda(lambda)) => {
+            if let Some(pattern) = lambda.arg().and_then(Pattern::cast) {
+                for entry in pattern.entries() {
+                    if let Some(name) = entry.name() {
+                        let mut children = Vec::new();
+                        if let Some(default) = entry.default() {
+                            collect(code, &default, &mut children);
+                        }
+                        symbols.push(DocumentSymbol {
+                            detail: Some(String::from(ARGUMENT)),
+                            ..symbol(
+                                code,
+                                name.as_str().into(),
+                                SymbolKind::Variable,
+                                entry.node(),
+                                name.node(),
+                                children,
+                            )
+                        });
+                    }
+                }
+                if let Some(at) = pattern.at() {
+                    symbols.push(DocumentSymbol {
+                        detail: Some(String::from(ARGUMENT)),
+                        ..symbol(
+                            code,
+                            at.as_str().into(),
+                            SymbolKind::Variable,
+                            at.node(),
+                            at.node(),
+                            Vec::new(),
+                        )
+                    });
+                }
+            }
+            if let Some(body) = lambda.body() {
+                collect(code, &body, symbols);
+            }
+        }
+        _ => {
+            for child in node.children() {
+                collect(code, &child, symbols);
+            }
+        }
+    }
+}
+
+/// Pushes a symbol for every binding of a `let` or a set, in source order.
+/// Anything else, such as the body of a `let`, is searched for more symbols.
+fn collect_entries(
+    code: &str,
+    node: &SyntaxNode,
+    kind: SymbolKind,
+    symbols: &mut Vec<DocumentSymbol>,
+) {
+    for child in node.children() {
+        if let Some(entry) = KeyValue::cast(child.clone()) {
+            let (key, value) = match (entry.key(), entry.value()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => continue,
+            };
+            let name = key.path().map(|part| part.text().to_string()).join(".");
+            if name.is_empty() {
+                continue;
+            }
+            let mut children = Vec::new();
+            collect(code, &value, &mut children);
+            symbols.push(symbol(
+                code,
+                name,
+                kind_of(&value, kind),
+                entry.node(),
+                key.node(),
+                children,
+            ));
+        } else if let Some(inherit) = Inherit::cast(child.clone()) {
+            for ident in inherit.idents() {
+                symbols.push(symbol(
+                    code,
+                    ident.as_str().into(),
+                    kind,
+                    ident.node(),
+                    ident.node(),
+                    Vec::new(),
+                ));
+            }
+        } else {
+            collect(code, &child, symbols);
+        }
+    }
+}
+
+fn kind_of(value: &SyntaxNode, fallback: SymbolKind) -> SymbolKind {
+    match value.kind() {
+        SyntaxKind::NODE_LAMBDA => SymbolKind::Function,
+        SyntaxKind::NODE_ATTR_SET => SymbolKind::Object,
+        SyntaxKind::NODE_LIST => SymbolKind::Array,
+        SyntaxKind::NODE_PAREN => Paren::cast(value.clone())
+            .and_then(|paren| paren.inner())
+            .map_or(fallback, |inner| kind_of(&inner, fallback)),
+        _ => fallback,
+    }
+}
+
+fn symbol(
+    code: &str,
+    name: String,
+    kind: SymbolKind,
+    node: &SyntaxNode,
+    selection: &SyntaxNode,
+    children: Vec<DocumentSymbol>,
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name,
+        detail: None,
+        kind,
+        deprecated: None,
+        range: utils::range(code, node.text_range()),
+        selection_range: utils::range(code, selection.text_range()),
+        children: if children.is_empty() {
+            None
+        } else {
+            Some(children)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(symbols: &[DocumentSymbol]) -> Vec<&str> {
+        symbols.iter().map(|symbol| symbol.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_nested_symbols() {
+        let code = "{ pkgs, lib ? null, ... }:\nlet\n  f = { a }: a;\n  inherit (lib) mkIf;\nin {\n  services.foo = { enable = true; };\n  value = 1;\n}";
+        let root = rnix::parse(code).node();
+        let symbols = document_symbols(code, &root);
+        assert_eq!(vec!["pkgs", "lib", "f", "mkIf", "services.foo", "value"], names(&symbols));
+
+        assert_eq!(SymbolKind::Function, symbols[2].kind);
+        assert_eq!(Some(ARGUMENT), symbols[0].detail.as_deref());
+        assert_eq!(vec!["a"], names(symbols[2].children.as_ref().unwrap()));
+        assert_eq!(SymbolKind::Variable, symbols[3].kind);
+
+        assert_eq!(SymbolKind::Object, symbols[4].kind);
+        assert_eq!(vec!["enable"], names(symbols[4].children.as_ref().unwrap()));
+        assert_eq!(SymbolKind::Property, symbols[5].kind);
+        assert!(symbols[5].children.is_none());
+    }
+}
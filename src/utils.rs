@@ -1,5 +1,9 @@
+use dirs::home_dir;
 use lsp_types::*;
-use rnix::{types::*, SyntaxNode, TextRange, TextSize, TokenAtOffset};
+use rnix::{
+    parser::ParseError, types::*, value::Anchor, SyntaxKind, SyntaxNode, TextRange, TextSize,
+    TokenAtOffset,
+};
 use std::{
     collections::HashMap,
     convert::TryFrom,
@@ -41,6 +45,16 @@ pub fn uri_path(uri: &Url) -> Option<PathBuf> {
     }
     Some(PathBuf::from(uri.path()))
 }
+/// Where a path literal in the file at `uri` points, if it can be known
+/// without evaluating anything
+pub fn resolve_path(uri: &Url, anchor: Anchor, path: &str) -> Option<PathBuf> {
+    match anchor {
+        Anchor::Absolute => Some(PathBuf::from(path)),
+        Anchor::Relative => uri.to_file_path().ok()?.parent().map(|dir| dir.join(path)),
+        Anchor::Home => home_dir().map(|home| home.join(path)),
+        Anchor::Store => None,
+    }
+}
 pub fn lookup_pos(code: &str, pos: Position) -> Option<usize> {
     let mut lines = code.split('\n');
 
@@ -78,6 +92,25 @@ pub fn range(code: &str, range: TextRange) -> Range {
         end: offset_to_pos(code, usize::from(range.end())),
     }
 }
+/// Where to report a parse error. Errors without a range of their own go at
+/// the end of the file if it ended too early, and otherwise on the first
+/// error node, or the start of the file if there is none.
+pub fn error_range(root: &SyntaxNode, err: &ParseError) -> TextRange {
+    match err {
+        ParseError::Unexpected(range)
+        | ParseError::UnexpectedDoubleBind(range)
+        | ParseError::UnexpectedExtra(range)
+        | ParseError::UnexpectedWanted(_, range, _)
+        | ParseError::DuplicatedArgs(range, _) => *range,
+        ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_) => {
+            TextRange::empty(root.text_range().end())
+        }
+        _ => root
+            .descendants()
+            .find(|node| node.kind() == SyntaxKind::NODE_ERROR)
+            .map_or_else(|| TextRange::empty(TextSize::from(0)), |node| node.text_range()),
+    }
+}
 pub struct CursorInfo {
     pub path: Vec<String>,
     pub ident: Ident,
@@ -196,7 +229,7 @@ pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Var {
     pub file: Rc<Url>,
     pub set: SyntaxNode,
@@ -228,6 +261,22 @@ pub fn populate<T: EntryHolder>(
             }
         }
     }
+    for inherit in set.inherits() {
+        for ident in inherit.idents() {
+            if !scope.contains_key(ident.as_str()) {
+                scope.insert(
+                    ident.as_str().into(),
+                    Var {
+                        file: Rc::clone(file),
+                        set: set.node().to_owned(),
+                        key: ident.node().to_owned(),
+                        value: None,
+                        datatype,
+                    },
+                );
+            }
+        }
+    }
     Some(())
 }
 pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> Option<HashMap<String, Var>> {
@@ -235,72 +284,203 @@ pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> Option<HashMap<String, Var
 
     let mut current = Some(node);
     while let Some(node) = current {
-        match ParsedType::try_from(node.clone()) {
-            Ok(ParsedType::LetIn(let_in)) => {
-                populate(&file, &mut scope, &let_in, Datatype::Variable);
-            }
-            Ok(ParsedType::LegacyLet(let_)) => {
-                populate(&file, &mut scope, &let_, Datatype::Variable);
+        add_bindings(file, &mut scope, &node)?;
+        current = node.parent();
+    }
+
+    Some(scope)
+}
+/// Adds the variables that `node` brings into scope for its children,
+/// keeping any of the same name that are already there.
+pub fn add_bindings(
+    file: &Rc<Url>,
+    scope: &mut HashMap<String, Var>,
+    node: &SyntaxNode,
+) -> Option<()> {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::LetIn(let_in)) => {
+            populate(file, scope, &let_in, Datatype::Variable);
+        }
+        Ok(ParsedType::LegacyLet(let_)) => {
+            populate(file, scope, &let_, Datatype::Variable);
+        }
+        Ok(ParsedType::AttrSet(set)) => {
+            if set.recursive() {
+                populate(file, scope, &set, Datatype::Attribute);
             }
-            Ok(ParsedType::AttrSet(set)) => {
-                if set.recursive() {
-                    populate(&file, &mut scope, &set, Datatype::Attribute);
+        }
+        Ok(ParsedType::Lambda(lambda)) => match ParsedType::try_from(lambda.arg()?) {
+            Ok(ParsedType::Ident(ident)) => {
+                if !scope.contains_key(ident.as_str()) {
+                    scope.insert(
+                        ident.as_str().into(),
+                        Var {
+                            file: Rc::clone(file),
+                            set: lambda.node().clone(),
+                            key: ident.node().clone(),
+                            value: None,
+                            datatype: Datatype::Lambda,
+                        },
+                    );
                 }
             }
-            Ok(ParsedType::Lambda(lambda)) => match ParsedType::try_from(lambda.arg()?) {
-                Ok(ParsedType::Ident(ident)) => {
+            Ok(ParsedType::Pattern(pattern)) => {
+                for entry in pattern.entries() {
+                    let ident = entry.name()?;
                     if !scope.contains_key(ident.as_str()) {
                         scope.insert(
                             ident.as_str().into(),
                             Var {
-                                file: Rc::clone(&file),
-                                set: lambda.node().clone(),
-                                key: ident.node().clone(),
+                                file: Rc::clone(file),
+                                set: lambda.node().to_owned(),
+                                key: ident.node().to_owned(),
                                 value: None,
                                 datatype: Datatype::Lambda,
                             },
                         );
                     }
                 }
-                Ok(ParsedType::Pattern(pattern)) => {
-                    for entry in pattern.entries() {
-                        let ident = entry.name()?;
-                        if !scope.contains_key(ident.as_str()) {
-                            scope.insert(
-                                ident.as_str().into(),
-                                Var {
-                                    file: Rc::clone(&file),
-                                    set: lambda.node().to_owned(),
-                                    key: ident.node().to_owned(),
-                                    value: None,
-                                    datatype: Datatype::Lambda,
-                                },
-                            );
-                        }
-                    }
-                    if let Some(ident) = pattern.at() {
-                        if !scope.contains_key(ident.as_str()) {
-                            scope.insert(
-                                ident.as_str().into(),
-                                Var {
-                                    file: Rc::clone(&file),
-                                    set: lambda.node().to_owned(),
-                                    key: ident.node().to_owned(),
-                                    value: None,
-                                    datatype: Datatype::Lambda,
-                                },
-                            );
-                        }
+                if let Some(ident) = pattern.at() {
+                    if !scope.contains_key(ident.as_str()) {
+                        scope.insert(
+                            ident.as_str().into(),
+                            Var {
+                                file: Rc::clone(file),
+                                set: lambda.node().to_owned(),
+                                key: ident.node().to_owned(),
+                                value: None,
+                                datatype: Datatype::Lambda,
+                            },
+                        );
                     }
                 }
-                _ => (),
-            },
+            }
             _ => (),
+        },
+        _ => (),
+    }
+    Some(())
+}
+/// Calls `visit` on every identifier below `node` in order, along with the
+/// variables it can see. Scopes are built up on the way down instead of
+/// being looked up again for every identifier.
+pub fn walk_scopes(
+    file: &Rc<Url>,
+    node: &SyntaxNode,
+    outer: &HashMap<String, Var>,
+    visit: &mut dyn FnMut(&Ident, &HashMap<String, Var>),
+) {
+    let mut own = HashMap::new();
+    add_bindings(file, &mut own, node);
+    let extended;
+    let scope = if own.is_empty() {
+        outer
+    } else {
+        let mut scope = outer.clone();
+        scope.extend(own);
+        extended = scope;
+        &extended
+    };
+
+    for child in node.children() {
+        if let Some(inherit) = Inherit::cast(child.clone()) {
+            let from = inherit.from();
+            if let Some(from) = &from {
+                walk_scopes(file, from.node(), scope, visit);
+            }
+            // `inherit a;` reads `a` from outside the set or `let` it's in
+            let reads = if from.is_none() { outer } else { scope };
+            for ident in inherit.idents() {
+                visit(&ident, reads);
+            }
+        } else if let Some(ident) = Ident::cast(child.clone()) {
+            visit(&ident, scope);
+        } else {
+            walk_scopes(file, &child, scope, visit);
         }
-        current = node.parent();
     }
-
-    Some(scope)
+}
+/// Returns `false` if the identifier names an attribute, a binding or a
+/// lambda argument rather than reading a variable.
+pub fn is_variable_use(ident: &Ident) -> bool {
+    let node = ident.node();
+    let parent = match node.parent() {
+        Some(parent) => parent,
+        None => return true,
+    };
+    match parent.kind() {
+        SyntaxKind::NODE_KEY
+        | SyntaxKind::NODE_PAT_ENTRY
+        | SyntaxKind::NODE_PAT_BIND
+        | SyntaxKind::NODE_PATTERN => false,
+        SyntaxKind::NODE_LAMBDA => Lambda::cast(parent)
+            .and_then(|lambda| lambda.arg())
+            .map_or(true, |arg| arg != *node),
+        SyntaxKind::NODE_SELECT => Select::cast(parent)
+            .and_then(|select| select.index())
+            .map_or(true, |index| index != *node),
+        // `inherit a;` reads `a` from the surrounding scope, while
+        // `inherit (set) a;` doesn't look at the scope at all.
+        SyntaxKind::NODE_INHERIT => Inherit::cast(parent).map_or(false, |inherit| inherit.from().is_none()),
+        _ => true,
+    }
+}
+/// Returns true if the node is inside the body of a `with`, where names that
+/// don't resolve lexically may come from the `with` namespace.
+pub fn in_with_scope(node: &SyntaxNode) -> bool {
+    node.ancestors().filter_map(With::cast).any(|with| {
+        with.body()
+            .map_or(false, |body| body.text_range().contains_range(node.text_range()))
+    })
+}
+/// Where to look up a variable from. `inherit a;` in a `let` or `rec` set
+/// reads `a` from outside, rather than from the binding it creates.
+pub fn scope_node(ident: &Ident) -> Option<SyntaxNode> {
+    let parent = ident.node().parent()?;
+    if parent.kind() == SyntaxKind::NODE_INHERIT {
+        parent.parent()?.parent()
+    } else {
+        Some(ident.node().clone())
+    }
+}
+/// Finds the variable an identifier refers to. This works both on uses of a
+/// variable and on the identifier that defines it. The name in `inherit a;`
+/// counts as a use of the `a` it is inherited from.
+pub fn binding_for(file: &Rc<Url>, ident: &Ident) -> Option<Var> {
+    if is_variable_use(ident) {
+        scope_for(file, scope_node(ident)?)?.remove(ident.as_str())
+    } else {
+        declaration(file, ident)
+    }
+}
+/// Finds the variable an identifier defines, including the binding created
+/// by `inherit a;`
+pub fn declaration(file: &Rc<Url>, ident: &Ident) -> Option<Var> {
+    let var = scope_for(file, ident.node().clone())?.remove(ident.as_str())?;
+    if var.key == *ident.node() {
+        Some(var)
+    } else {
+        None
+    }
+}
+/// Collects every read of `var`. Uses that are shadowed by an inner binding
+/// of the same name are skipped.
+pub fn references_to(file: &Rc<Url>, var: &Var) -> Vec<Ident> {
+    let name = match Ident::cast(var.key.clone()) {
+        Some(ident) => ident.as_str().to_owned(),
+        None => return Vec::new(),
+    };
+    var.set
+        .descendants()
+        .filter_map(Ident::cast)
+        .filter(|ident| ident.as_str() == name && is_variable_use(ident))
+        .filter(|ident| {
+            scope_node(ident)
+                .and_then(|node| scope_for(file, node))
+                .and_then(|mut scope| scope.remove(&name))
+                .map_or(false, |found| found.key == var.key)
+        })
+        .collect()
 }
 pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position) -> Option<SelectionRange> {
     let pos = lookup_pos(content, pos)?;
@@ -331,6 +511,30 @@ pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position) -> Opti
     root.map(|b| *b)
 }
 
+/// A directory for tests to create files in. It is removed when the guard
+/// is dropped, so failing assertions don't leave it behind.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("rnix-lsp-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -475,4 +679,60 @@ mod tests {
         let ident_ = ident.unwrap();
         assert_eq!(vec!["a"], ident_.path);
     }
+
+    #[test]
+    fn test_references_skip_shadowed() {
+        let expr = "let a = 1; b = a; in { c = a; d = let a = 2; in a; e = x: a; f = a: a; }";
+        let root = rnix::parse(expr).node();
+        let file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let ident = ident_at(&root, 4).unwrap().ident;
+        let var = binding_for(&file, &ident).expect("expected definition to resolve");
+        assert_eq!(var.key, *ident.node());
+
+        let uses = references_to(&file, &var);
+        let offsets = uses
+            .iter()
+            .map(|ident| usize::from(ident.node().text_range().start()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![15, 27, 58], offsets);
+
+        // Resolving from a use leads back to the same definition
+        let use_site = ident_at(&root, 27).unwrap().ident;
+        assert_eq!(var.key, binding_for(&file, &use_site).unwrap().key);
+    }
+
+    #[test]
+    fn test_references_through_inherit() {
+        let expr = "let a = 1; in { inherit a; b = let inherit a; in a; }";
+        let root = rnix::parse(expr).node();
+        let file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let offsets = |var: &Var| {
+            references_to(&file, var)
+                .iter()
+                .map(|ident| usize::from(ident.node().text_range().start()))
+                .collect::<Vec<_>>()
+        };
+
+        // `inherit a;` reads the outer `a`
+        let outer = binding_for(&file, &ident_at(&root, 4).unwrap().ident).unwrap();
+        assert_eq!(vec![24, 43], offsets(&outer));
+        let inherited = ident_at(&root, 24).unwrap().ident;
+        assert_eq!(outer.key, binding_for(&file, &inherited).unwrap().key);
+
+        // ... while also binding a new `a` within the `let`
+        let inner = binding_for(&file, &ident_at(&root, 49).unwrap().ident).unwrap();
+        assert_eq!(43, usize::from(inner.key.text_range().start()));
+        assert_eq!(vec![49], offsets(&inner));
+    }
+
+    #[test]
+    fn test_attribute_key_is_not_a_binding() {
+        let expr = "let a = 1; in { a = a; }";
+        let root = rnix::parse(expr).node();
+        let file = Rc::new(Url::parse("file:///default.nix").unwrap());
+        let key = ident_at(&root, 16).unwrap().ident;
+        assert!(binding_for(&file, &key).is_none());
+        let value = ident_at(&root, 20).unwrap().ident;
+        assert!(binding_for(&file, &value).is_some());
+    }
 }